@@ -29,8 +29,10 @@ fn main() {
     };
     decl.index_format = IndexFormat::Uint32;
 
-    atlas.add_mesh(&decl);
-    atlas.generate_simple(Default::default(), Default::default());
+    atlas.add_mesh(&decl).unwrap();
+    atlas
+        .generate_simple(Default::default(), Default::default(), Default::default())
+        .unwrap();
 
     let meshes = atlas.meshes();
 
@@ -0,0 +1,518 @@
+// Bounding volume hierarchy over the input triangles, for the ray casts of the bakers and closest
+// point queries
+
+use crate::math::*;
+
+const LEAF_SIZE: usize = 4;
+const BINS: usize = 12;
+
+#[derive(Debug, Copy, Clone)]
+struct Bounds {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Bounds {
+    fn empty() -> Self {
+        Bounds {
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+        }
+    }
+
+    fn grow(&mut self, p: Vec3) {
+        for (c, &v) in p.iter().enumerate() {
+            self.min[c] = self.min[c].min(v);
+            self.max[c] = self.max[c].max(v);
+        }
+    }
+
+    fn union(&mut self, b: &Bounds) {
+        for c in 0..3 {
+            self.min[c] = self.min[c].min(b.min[c]);
+            self.max[c] = self.max[c].max(b.max[c]);
+        }
+    }
+
+    fn area(&self) -> f32 {
+        let d = sub(self.max, self.min);
+        if d[0] < 0.0 {
+            return 0.0;
+        }
+        d[0] * d[1] + d[1] * d[2] + d[2] * d[0]
+    }
+
+    // Squared distance from `p` to the box, 0 inside
+    fn distance2(&self, p: Vec3) -> f32 {
+        let mut d = [0.0; 3];
+        for (c, d) in d.iter_mut().enumerate() {
+            *d = (self.min[c] - p[c]).max(p[c] - self.max[c]).max(0.0);
+        }
+        dot(d, d)
+    }
+
+    // Entry distance of the ray, if it hits the box before `t_max`
+    fn hit(&self, origin: Vec3, inv_dir: Vec3, t_max: f32) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = t_max;
+        for c in 0..3 {
+            let t0 = (self.min[c] - origin[c]) * inv_dir[c];
+            let t1 = (self.max[c] - origin[c]) * inv_dir[c];
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        if near <= far {
+            Some(near)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    bounds: Bounds,
+    first: u32, // first triangle of a leaf, or the first child of an inner node
+    count: u32, // 0 for inner nodes, whose children are `first` and `first + 1`
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Hit {
+    pub triangle: u32, // index into the triangles the bvh was built from
+    pub t: f32,
+    pub barycentrics: [f32; 3],
+}
+
+#[derive(Debug)]
+pub(crate) struct Bvh {
+    nodes: Vec<Node>,
+    triangles: Vec<[Vec3; 3]>, // in node order
+    ids: Vec<u32>,             // node order -> input triangle
+}
+
+impl Bvh {
+    pub fn new(triangles: &[[Vec3; 3]]) -> Self {
+        let mut ids: Vec<u32> = (0..triangles.len() as u32).collect();
+        let bounds: Vec<Bounds> = triangles
+            .iter()
+            .map(|t| {
+                let mut b = Bounds::empty();
+                t.iter().for_each(|&p| b.grow(p));
+                b
+            })
+            .collect();
+        let centroids: Vec<Vec3> = triangles
+            .iter()
+            .map(|t| scale(add(add(t[0], t[1]), t[2]), 1.0 / 3.0))
+            .collect();
+
+        let mut nodes = vec![Node {
+            bounds: Bounds::empty(),
+            first: 0,
+            count: ids.len() as u32,
+        }];
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let (first, count) = (nodes[node].first as usize, nodes[node].count as usize);
+            let range = first..first + count;
+            let mut node_bounds = Bounds::empty();
+            let mut centroid_bounds = Bounds::empty();
+            for &id in &ids[range.clone()] {
+                node_bounds.union(&bounds[id as usize]);
+                centroid_bounds.grow(centroids[id as usize]);
+            }
+            nodes[node].bounds = node_bounds;
+            if count <= LEAF_SIZE {
+                continue;
+            }
+
+            let split = match best_split(&ids[range.clone()], &bounds, &centroids, &centroid_bounds)
+            {
+                Some(split) => split,
+                None => continue,
+            };
+            let (axis, position) = split;
+            let mut left = 0;
+            for i in range.clone() {
+                if centroids[ids[i] as usize][axis] < position {
+                    ids.swap(i, first + left);
+                    left += 1;
+                }
+            }
+            // Coincident centroids, split in the middle
+            if left == 0 || left == count {
+                left = count / 2;
+            }
+
+            let child = nodes.len();
+            nodes.push(Node {
+                bounds: Bounds::empty(),
+                first: first as u32,
+                count: left as u32,
+            });
+            nodes.push(Node {
+                bounds: Bounds::empty(),
+                first: (first + left) as u32,
+                count: (count - left) as u32,
+            });
+            nodes[node].first = child as u32;
+            nodes[node].count = 0;
+            stack.push(child);
+            stack.push(child + 1);
+        }
+
+        Bvh {
+            nodes,
+            triangles: ids.iter().map(|&id| triangles[id as usize]).collect(),
+            ids,
+        }
+    }
+
+    // Distance rays start above the surface, so they don't hit the face they leave from
+    pub fn ray_offset(&self) -> f32 {
+        match self.nodes.first() {
+            Some(root) if !self.triangles.is_empty() => {
+                length(sub(root.bounds.max, root.bounds.min)) * 1e-4
+            }
+            _ => 0.0,
+        }
+    }
+
+    // Whether the ray hits any triangle in (0, t_max)
+    pub fn occluded(&self, origin: Vec3, dir: Vec3, t_max: f32) -> bool {
+        self.cast(origin, dir, t_max, true).is_some()
+    }
+
+    // Closest hit in (0, t_max)
+    pub fn intersect(&self, origin: Vec3, dir: Vec3, t_max: f32) -> Option<Hit> {
+        self.cast(origin, dir, t_max, false)
+    }
+
+    // Closest point to `p` on the triangles `accept` returns true for, `Hit::t` is the distance
+    pub fn closest<F>(&self, p: Vec3, accept: F) -> Option<Hit>
+    where
+        F: Fn(u32) -> bool,
+    {
+        if self.triangles.is_empty() {
+            return None;
+        }
+
+        let mut closest: Option<Hit> = None;
+        let mut best = f32::INFINITY; // squared distance
+        let mut stack = vec![0usize];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if node.bounds.distance2(p) >= best {
+                continue;
+            }
+
+            if node.count == 0 {
+                // Visit the nearer child first
+                let (a, b) = (node.first as usize, node.first as usize + 1);
+                let da = self.nodes[a].bounds.distance2(p);
+                let db = self.nodes[b].bounds.distance2(p);
+                if da < db {
+                    stack.extend_from_slice(&[b, a]);
+                } else {
+                    stack.extend_from_slice(&[a, b]);
+                }
+                continue;
+            }
+
+            for i in node.first..node.first + node.count {
+                let id = self.ids[i as usize];
+                if !accept(id) {
+                    continue;
+                }
+                let t = &self.triangles[i as usize];
+                let barycentrics = closest_point(t, p);
+                let q = add(
+                    add(scale(t[0], barycentrics[0]), scale(t[1], barycentrics[1])),
+                    scale(t[2], barycentrics[2]),
+                );
+                let d = sub(q, p);
+                let distance2 = dot(d, d);
+                if distance2 < best {
+                    best = distance2;
+                    closest = Some(Hit {
+                        triangle: id,
+                        t: distance2.sqrt(),
+                        barycentrics,
+                    });
+                }
+            }
+        }
+        closest
+    }
+
+    fn cast(&self, origin: Vec3, dir: Vec3, mut t_max: f32, any: bool) -> Option<Hit> {
+        if self.triangles.is_empty() {
+            return None;
+        }
+
+        let inv_dir = [1.0 / dir[0], 1.0 / dir[1], 1.0 / dir[2]];
+        let mut closest = None;
+        let mut stack = vec![0usize];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if node.bounds.hit(origin, inv_dir, t_max).is_none() {
+                continue;
+            }
+
+            if node.count == 0 {
+                // Visit the nearer child first
+                let (a, b) = (node.first as usize, node.first as usize + 1);
+                let ta = self.nodes[a].bounds.hit(origin, inv_dir, t_max);
+                let tb = self.nodes[b].bounds.hit(origin, inv_dir, t_max);
+                match (ta, tb) {
+                    (Some(ta), Some(tb)) if ta < tb => stack.extend_from_slice(&[b, a]),
+                    (Some(_), Some(_)) => stack.extend_from_slice(&[a, b]),
+                    (Some(_), None) => stack.push(a),
+                    (None, Some(_)) => stack.push(b),
+                    (None, None) => {}
+                }
+                continue;
+            }
+
+            for i in node.first..node.first + node.count {
+                let (t, u, v) = match intersect(&self.triangles[i as usize], origin, dir) {
+                    Some(hit) if hit.0 < t_max => hit,
+                    _ => continue,
+                };
+                t_max = t;
+                closest = Some(Hit {
+                    triangle: self.ids[i as usize],
+                    t,
+                    barycentrics: [1.0 - u - v, u, v],
+                });
+                if any {
+                    break;
+                }
+            }
+            if any && closest.is_some() {
+                break;
+            }
+        }
+        closest
+    }
+}
+
+// Axis and position of the binned surface area heuristic split, None when splitting doesn't pay off
+fn best_split(
+    ids: &[u32],
+    bounds: &[Bounds],
+    centroids: &[Vec3],
+    centroid_bounds: &Bounds,
+) -> Option<(usize, f32)> {
+    let mut best: Option<(f32, usize, f32)> = None;
+    let extents = centroid_bounds.min.iter().zip(&centroid_bounds.max);
+    for (axis, (&min, &max)) in extents.enumerate() {
+        if max <= min {
+            continue;
+        }
+        let bin_scale = BINS as f32 / (max - min);
+        let bin =
+            |id: u32| (((centroids[id as usize][axis] - min) * bin_scale) as usize).min(BINS - 1);
+
+        let mut bins = [(Bounds::empty(), 0usize); BINS];
+        for &id in ids {
+            let b = &mut bins[bin(id)];
+            b.0.union(&bounds[id as usize]);
+            b.1 += 1;
+        }
+
+        // Cost of splitting after every bin, sweeping from the right and then from the left
+        let mut right_costs = [0.0; BINS];
+        let mut acc = (Bounds::empty(), 0);
+        for i in (1..BINS).rev() {
+            acc.0.union(&bins[i].0);
+            acc.1 += bins[i].1;
+            right_costs[i - 1] = acc.0.area() * acc.1 as f32;
+        }
+        let mut acc = (Bounds::empty(), 0);
+        for i in 0..BINS - 1 {
+            acc.0.union(&bins[i].0);
+            acc.1 += bins[i].1;
+            let cost = acc.0.area() * acc.1 as f32 + right_costs[i];
+            if best.map(|b| cost < b.0).unwrap_or(true) {
+                best = Some((cost, axis, min + (i + 1) as f32 / bin_scale));
+            }
+        }
+    }
+
+    let mut total = Bounds::empty();
+    for &id in ids {
+        total.union(&bounds[id as usize]);
+    }
+    let leaf_cost = total.area() * ids.len() as f32;
+    best.filter(|b| b.0 < leaf_cost || ids.len() > LEAF_SIZE * 4)
+        .map(|b| (b.1, b.2))
+}
+
+// Möller-Trumbore, returns the distance and the barycentrics of the second and third vertex
+fn intersect(t: &[Vec3; 3], origin: Vec3, dir: Vec3) -> Option<(f32, f32, f32)> {
+    let e1 = sub(t[1], t[0]);
+    let e2 = sub(t[2], t[0]);
+    let p = cross(dir, e2);
+    let det = dot(e1, p);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = sub(origin, t[0]);
+    let u = dot(s, p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = cross(s, e1);
+    let v = dot(dir, q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = dot(e2, q) * inv_det;
+    if t > 0.0 {
+        Some((t, u, v))
+    } else {
+        None
+    }
+}
+
+// Barycentrics of the point of `t` closest to `p`, from Real-Time Collision Detection 5.1.5
+fn closest_point(t: &[Vec3; 3], p: Vec3) -> [f32; 3] {
+    let (a, b, c) = (t[0], t[1], t[2]);
+    let ab = sub(b, a);
+    let ac = sub(c, a);
+    let ap = sub(p, a);
+    let d1 = dot(ab, ap);
+    let d2 = dot(ac, ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return [1.0, 0.0, 0.0];
+    }
+
+    let bp = sub(p, b);
+    let d3 = dot(ab, bp);
+    let d4 = dot(ac, bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return [0.0, 1.0, 0.0];
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return [1.0 - v, v, 0.0];
+    }
+
+    let cp = sub(p, c);
+    let d5 = dot(ab, cp);
+    let d6 = dot(ac, cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return [0.0, 0.0, 1.0];
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return [1.0 - w, 0.0, w];
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return [0.0, 1.0 - w, w];
+    }
+
+    let denom = va + vb + vc;
+    if denom == 0.0 || !denom.is_finite() {
+        // Degenerate triangle, fall back to the closest vertex
+        let distance2 = |q: Vec3| dot(sub(q, p), sub(q, p));
+        let mut barycentrics = [0.0; 3];
+        let nearest = (0..3)
+            .min_by(|&i, &j| {
+                distance2(t[i])
+                    .partial_cmp(&distance2(t[j]))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .unwrap();
+        barycentrics[nearest] = 1.0;
+        return barycentrics;
+    }
+    let v = vb / denom;
+    let w = vc / denom;
+    [1.0 - v - w, v, w]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points in [0, 1)^3 from a fixed linear congruential sequence
+    fn points(count: usize, mut seed: u32) -> Vec<Vec3> {
+        let mut next = || {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f32 / (1 << 24) as f32
+        };
+        (0..count).map(|_| [next(), next(), next()]).collect()
+    }
+
+    fn triangles() -> Vec<[Vec3; 3]> {
+        points(200, 1)
+            .iter()
+            .zip(points(400, 2).chunks_exact(2))
+            .map(|(&p, e)| {
+                let edge = |e: Vec3| add(p, scale(sub(e, [0.5; 3]), 0.2));
+                [p, edge(e[0]), edge(e[1])]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rays_match_brute_force() {
+        let triangles = triangles();
+        let bvh = Bvh::new(&triangles);
+        let mut hits = 0;
+        for (&origin, &target) in points(300, 3).iter().zip(&points(300, 4)) {
+            let dir = normalize(sub(target, origin));
+            let expected = triangles
+                .iter()
+                .enumerate()
+                .filter_map(|(i, t)| intersect(t, origin, dir).map(|hit| (hit.0, i as u32)))
+                .fold(None, |best: Option<(f32, u32)>, hit| match best {
+                    Some(best) if best.0 <= hit.0 => Some(best),
+                    _ => Some(hit),
+                });
+
+            let hit = bvh.intersect(origin, dir, f32::INFINITY);
+            assert_eq!(hit.map(|hit| (hit.t, hit.triangle)), expected);
+            let near = expected.is_some_and(|hit| hit.0 < 0.5);
+            assert_eq!(bvh.occluded(origin, dir, 0.5), near);
+            hits += expected.is_some() as u32;
+        }
+        assert!(hits > 50, "{}", hits);
+    }
+
+    #[test]
+    fn closest_points_match_brute_force() {
+        let triangles = triangles();
+        let bvh = Bvh::new(&triangles);
+        let distance = |t: &[Vec3; 3], p: Vec3| {
+            let b = closest_point(t, p);
+            let q = add(add(scale(t[0], b[0]), scale(t[1], b[1])), scale(t[2], b[2]));
+            length(sub(q, p))
+        };
+
+        for &p in &points(300, 5) {
+            let p = sub(scale(p, 1.4), [0.2; 3]);
+            let expected = triangles
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| i % 2 == 0)
+                .map(|(_, t)| distance(t, p))
+                .fold(f32::INFINITY, f32::min);
+
+            let hit = bvh.closest(p, |i| i % 2 == 0).unwrap();
+            assert_eq!(hit.triangle % 2, 0);
+            assert!((hit.t - expected).abs() < 1e-6, "{} {}", hit.t, expected);
+        }
+        assert!(Bvh::new(&[]).closest([0.0; 3], |_| true).is_none());
+    }
+}
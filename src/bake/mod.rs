@@ -0,0 +1,915 @@
+// Baking into the generated atlas. Images cover a single atlas page and are indexed by texel,
+// with uvs in texels as in the output vertices.
+
+pub(crate) mod bvh;
+
+use self::bvh::Bvh;
+use crate::input::InputMesh;
+use crate::math::*;
+use crate::param::sparse::{least_squares, SparseMatrix};
+use crate::raster::rasterize_triangle;
+use crate::tangent::vertex_normals;
+use crate::{AddMeshError, Mesh, MeshDecl, Xatlas};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct Image<T> {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<T>, // rows of `width` texels
+}
+
+impl<T: Copy + Default> Image<T> {
+    pub fn new(width: u32, height: u32) -> Self {
+        Image {
+            width,
+            height,
+            data: vec![T::default(); width as usize * height as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> T {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, value: T) {
+        self.data[(y * self.width + x) as usize] = value;
+    }
+}
+
+// Texel formats that can be filtered, all math happens on up to four f32 channels
+pub trait Texel: Copy + Default {
+    fn to_f32s(self) -> [f32; 4];
+    fn from_f32s(v: [f32; 4]) -> Self;
+}
+
+impl Texel for f32 {
+    fn to_f32s(self) -> [f32; 4] {
+        [self, 0.0, 0.0, 0.0]
+    }
+
+    fn from_f32s(v: [f32; 4]) -> Self {
+        v[0]
+    }
+}
+
+impl Texel for [f32; 3] {
+    fn to_f32s(self) -> [f32; 4] {
+        [self[0], self[1], self[2], 0.0]
+    }
+
+    fn from_f32s(v: [f32; 4]) -> Self {
+        [v[0], v[1], v[2]]
+    }
+}
+
+impl Texel for [f32; 4] {
+    fn to_f32s(self) -> [f32; 4] {
+        self
+    }
+
+    fn from_f32s(v: [f32; 4]) -> Self {
+        v
+    }
+}
+
+impl Texel for [u8; 4] {
+    fn to_f32s(self) -> [f32; 4] {
+        [
+            self[0] as f32,
+            self[1] as f32,
+            self[2] as f32,
+            self[3] as f32,
+        ]
+    }
+
+    fn from_f32s(v: [f32; 4]) -> Self {
+        let c = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+        [c(v[0]), c(v[1]), c(v[2]), c(v[3])]
+    }
+}
+
+// Calls `f` with the mesh index, the triangle index and the texels covered by every triangle on
+// `page`. Rasterization is conservative so thin triangles still get texels, which means texels
+// along chart edges can be visited by more than one triangle.
+pub(crate) fn rasterize_page<F>(meshes: &[Mesh], page: u32, size: [u32; 2], mut f: F)
+where
+    F: FnMut(usize, usize, u32, u32),
+{
+    for (mesh_index, mesh) in meshes.iter().enumerate() {
+        for (face, t) in mesh.indices.chunks_exact(3).enumerate() {
+            let vertices = [
+                mesh.vertices[t[0] as usize],
+                mesh.vertices[t[1] as usize],
+                mesh.vertices[t[2] as usize],
+            ];
+            if vertices[0].atlas_index != page {
+                continue;
+            }
+            let uvs = [vertices[0].uv, vertices[1].uv, vertices[2].uv];
+            rasterize_triangle(uvs, size, true, |x, y| f(mesh_index, face, x, y));
+        }
+    }
+}
+
+// The triangle a texel center gets its value from, with the barycentrics of the center in it
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct TexelSample {
+    pub mesh: usize,
+    pub face: usize,
+    pub barycentrics: [f32; 3],
+}
+
+// One sample per texel of `page`. Texels that conservative rasterization adds around a chart have
+// their center outside of every triangle, those take the closest triangle with the barycentrics
+// clamped onto it.
+pub(crate) fn sample_page(meshes: &[Mesh], page: u32, size: [u32; 2]) -> Vec<Option<TexelSample>> {
+    let mut samples: Vec<Option<TexelSample>> = vec![None; size[0] as usize * size[1] as usize];
+    let mut coverage = vec![f32::MIN; samples.len()];
+
+    rasterize_page(meshes, page, size, |mesh_index, face, x, y| {
+        let mesh = &meshes[mesh_index];
+        let uv = |c: usize| mesh.vertices[mesh.indices[face * 3 + c] as usize].uv;
+        let center = [x as f32 + 0.5, y as f32 + 0.5];
+        let b = barycentrics([uv(0), uv(1), uv(2)], center);
+
+        // The lowest weight is the distance outside the triangle
+        let i = (y * size[0] + x) as usize;
+        let inside = b[0].min(b[1]).min(b[2]);
+        if inside > coverage[i] {
+            coverage[i] = inside;
+            let clamped = [b[0].max(0.0), b[1].max(0.0), b[2].max(0.0)];
+            let sum = clamped[0] + clamped[1] + clamped[2];
+            samples[i] = Some(TexelSample {
+                mesh: mesh_index,
+                face,
+                barycentrics: [clamped[0] / sum, clamped[1] / sum, clamped[2] / sum],
+            });
+        }
+    });
+
+    samples
+}
+
+fn barycentrics(t: [[f32; 2]; 3], p: [f32; 2]) -> [f32; 3] {
+    let area = signed_area2(t[0], t[1], t[2]);
+    if area == 0.0 || !area.is_finite() {
+        return [1.0 / 3.0; 3];
+    }
+    let b0 = signed_area2(p, t[1], t[2]) / area;
+    let b1 = signed_area2(t[0], p, t[2]) / area;
+    [b0, b1, 1.0 - b0 - b1]
+}
+
+// Bilinear filtered lookup with wrapping, `uv` is normalized with (0, 0) the corner of the first texel
+pub fn sample<T: Texel>(image: &Image<T>, uv: [f32; 2]) -> T {
+    if image.width == 0 || image.height == 0 {
+        return T::default();
+    }
+
+    let x = uv[0] * image.width as f32 - 0.5;
+    let y = uv[1] * image.height as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let texel = |x: f32, y: f32| {
+        let x = (x as i64).rem_euclid(image.width as i64) as u32;
+        let y = (y as i64).rem_euclid(image.height as i64) as u32;
+        image.get(x, y).to_f32s()
+    };
+
+    let (a, b, c, d) = (
+        texel(x0, y0),
+        texel(x0 + 1.0, y0),
+        texel(x0, y0 + 1.0),
+        texel(x0 + 1.0, y0 + 1.0),
+    );
+    let mut v = [0.0; 4];
+    for i in 0..4 {
+        let top = a[i] + (b[i] - a[i]) * fx;
+        let bottom = c[i] + (d[i] - c[i]) * fx;
+        v[i] = top + (bottom - top) * fy;
+    }
+    T::from_f32s(v)
+}
+
+// Resamples a texture mapped with the original uvs (one per input vertex, looked up through
+// `Vertex::xref`) into the atlas layout of `mesh`, returning an image for every page up to the
+// last one holding a chart of the mesh.
+pub fn transfer_texture<T: Texel>(
+    src_image: &Image<T>,
+    src_uvs: &[[f32; 2]],
+    mesh: &Mesh,
+    atlas_size: [u32; 2],
+) -> Vec<Image<T>> {
+    // Not from the vertices, those outside of every chart have an atlas index of u32::MAX
+    let pages = mesh
+        .charts
+        .iter()
+        .map(|chart| chart.atlas_index + 1)
+        .max()
+        .unwrap_or(0);
+    let meshes = std::slice::from_ref(mesh);
+
+    (0..pages)
+        .map(|page| {
+            let mut image = Image::new(atlas_size[0], atlas_size[1]);
+            for (i, texel) in sample_page(meshes, page, atlas_size).iter().enumerate() {
+                let texel = match texel {
+                    Some(texel) => texel,
+                    None => continue,
+                };
+                let mut uv = [0.0; 2];
+                for (c, &w) in texel.barycentrics.iter().enumerate() {
+                    let xref = mesh.vertices[mesh.indices[texel.face * 3 + c] as usize].xref;
+                    let src = src_uvs[xref as usize];
+                    uv = [uv[0] + src[0] * w, uv[1] + src[1] * w];
+                }
+                image.data[i] = sample(src_image, uv);
+            }
+            image
+        })
+        .collect()
+}
+
+// Texels of `page` that are covered by a chart, and so written by the bakers
+pub fn chart_mask(atlas: &Xatlas, page: u32) -> Image<bool> {
+    let mut mask = Image::new(atlas.width(), atlas.height());
+    let size = [mask.width, mask.height];
+    rasterize_page(&atlas.meshes(), page, size, |_, _, x, y| {
+        mask.set(x, y, true)
+    });
+    mask
+}
+
+// Grows the valid texels of `image` into the gutters, every iteration fills the texels next to a
+// valid one with the average of their valid neighbours. Texels outside `chart_mask` are
+// considered empty and get overwritten.
+pub fn dilate<T: Texel>(image: &mut Image<T>, chart_mask: &Image<bool>, iterations: u32) {
+    let (width, height) = (image.width as i64, image.height as i64);
+    let mut valid = chart_mask.data.clone();
+    let mut border: Vec<usize> = vec![];
+
+    for _ in 0..iterations {
+        border.clear();
+        for y in 0..height {
+            for x in 0..width {
+                let i = (y * width + x) as usize;
+                if valid[i] {
+                    continue;
+                }
+
+                let mut sum = [0.0; 4];
+                let mut count = 0;
+                for ny in (y - 1).max(0)..=(y + 1).min(height - 1) {
+                    for nx in (x - 1).max(0)..=(x + 1).min(width - 1) {
+                        let n = (ny * width + nx) as usize;
+                        if valid[n] {
+                            let v = image.data[n].to_f32s();
+                            for c in 0..4 {
+                                sum[c] += v[c];
+                            }
+                            count += 1;
+                        }
+                    }
+                }
+                if count > 0 {
+                    let s = 1.0 / count as f32;
+                    image.data[i] = T::from_f32s([sum[0] * s, sum[1] * s, sum[2] * s, sum[3] * s]);
+                    border.push(i);
+                }
+            }
+        }
+
+        if border.is_empty() {
+            break;
+        }
+        // Only now, so texels filled in this iteration don't feed each other
+        for &i in &border {
+            valid[i] = true;
+        }
+    }
+}
+
+// How much more matching the two sides of a seam weighs than keeping the original texel values
+const SEAM_WEIGHT: f64 = 16.0;
+
+// Blends the texels along the seams of `mesh` on `page` so both sides of a seam filter to the same
+// values. Seams are edges of the input that were split into two output edges, which share their
+// `Vertex::xref`s but not their uvs. Every seam is sampled a few times per texel of length and
+// the texels under the bilinear footprints are solved for in a least squares sense, matching the
+// two sides while staying close to their original values.
+pub fn stitch_seams<T: Texel>(image: &mut Image<T>, mesh: &Mesh, page: u32) {
+    if image.width == 0 || image.height == 0 {
+        return;
+    }
+
+    // Output edges by the input edge they came from, as output vertices in the order of the xrefs
+    let mut edges: HashMap<(u32, u32), Vec<[u32; 2]>> = HashMap::new();
+    for t in mesh.indices.chunks_exact(3) {
+        if mesh.vertices[t[0] as usize].atlas_index != page {
+            continue;
+        }
+        for i in 0..3 {
+            let (a, b) = (t[i], t[(i + 1) % 3]);
+            let (xa, xb) = (
+                mesh.vertices[a as usize].xref,
+                mesh.vertices[b as usize].xref,
+            );
+            let (key, edge) = if xa < xb {
+                ((xa, xb), [a, b])
+            } else {
+                ((xb, xa), [b, a])
+            };
+            edges.entry(key).or_default().push(edge);
+        }
+    }
+
+    let mut variables: HashMap<usize, usize> = HashMap::new();
+    let mut texels: Vec<usize> = vec![];
+    let mut constraints: Vec<Vec<(usize, f64)>> = vec![];
+    let mut footprint = |uv: Vec2, sign: f64, row: &mut Vec<(usize, f64)>| {
+        let x = uv[0] - 0.5;
+        let y = uv[1] - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = ((x - x0) as f64, (y - y0) as f64);
+        for &(dx, dy, w) in &[
+            (0, 0, (1.0 - fx) * (1.0 - fy)),
+            (1, 0, fx * (1.0 - fy)),
+            (0, 1, (1.0 - fx) * fy),
+            (1, 1, fx * fy),
+        ] {
+            let tx = (x0 as i64 + dx).clamp(0, image.width as i64 - 1) as usize;
+            let ty = (y0 as i64 + dy).clamp(0, image.height as i64 - 1) as usize;
+            let texel = ty * image.width as usize + tx;
+            let variable = *variables.entry(texel).or_insert_with(|| {
+                texels.push(texel);
+                texels.len() - 1
+            });
+            row.push((variable, w * sign));
+        }
+    };
+
+    for sides in edges.values() {
+        if sides.len() != 2 {
+            continue;
+        }
+        let uv = |v: u32| mesh.vertices[v as usize].uv;
+        let (a, b) = (
+            [uv(sides[0][0]), uv(sides[0][1])],
+            [uv(sides[1][0]), uv(sides[1][1])],
+        );
+        if a == b {
+            continue;
+        }
+
+        let texel_length = length2(sub2(a[1], a[0])).max(length2(sub2(b[1], b[0])));
+        let samples = (texel_length * 3.0).ceil().max(1.0) as usize;
+        for s in 0..samples {
+            let t = (s as f32 + 0.5) / samples as f32;
+            let lerp = |e: [Vec2; 2]| {
+                [
+                    e[0][0] + (e[1][0] - e[0][0]) * t,
+                    e[0][1] + (e[1][1] - e[0][1]) * t,
+                ]
+            };
+            let mut row = vec![];
+            footprint(lerp(a), 1.0, &mut row);
+            footprint(lerp(b), -1.0, &mut row);
+            constraints.push(row);
+        }
+    }
+    if constraints.is_empty() {
+        return;
+    }
+
+    // An identity row per texel followed by the weighted seam rows
+    let n = texels.len();
+    let weight = SEAM_WEIGHT.sqrt();
+    let mut triplets: Vec<(usize, usize, f64)> = (0..n).map(|i| (i, i, 1.0)).collect();
+    for (r, row) in constraints.iter().enumerate() {
+        triplets.extend(row.iter().map(|&(c, w)| (n + r, c, w * weight)));
+    }
+    let matrix = SparseMatrix::from_triplets(n + constraints.len(), n, triplets);
+
+    let original: Vec<[f32; 4]> = texels.iter().map(|&t| image.data[t].to_f32s()).collect();
+    let mut solved = original.clone();
+    for channel in 0..4 {
+        let mut b = vec![0.0; matrix.rows];
+        for (i, value) in original.iter().enumerate() {
+            b[i] = value[channel] as f64;
+        }
+        let mut x: Vec<f64> = b[..n].to_vec();
+        least_squares(&matrix, &b, &mut x, 200, 1e-6);
+        for (i, value) in x.iter().enumerate() {
+            solved[i][channel] = *value as f32;
+        }
+    }
+    for (&texel, value) in texels.iter().zip(solved) {
+        image.data[texel] = T::from_f32s(value);
+    }
+}
+
+// World space position and normals of the input surface under a texel
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SurfacePoint {
+    pub position: Vec3,
+    pub normal: Vec3, // interpolated input normal, or the face normal without input normals
+    pub face_normal: Vec3, // on the same side as `normal`
+}
+
+pub(crate) fn surface_point(mesh: &Mesh, input: &InputMesh, texel: &TexelSample) -> SurfacePoint {
+    let t = &mesh.indices[texel.face * 3..texel.face * 3 + 3];
+    let xref = |c: usize| mesh.vertices[t[c] as usize].xref;
+    input_point(input, [xref(0), xref(1), xref(2)], texel.barycentrics)
+}
+
+// The point with `barycentrics` in the triangle of input vertices `t`
+pub(crate) fn input_point(input: &InputMesh, t: [u32; 3], barycentrics: [f32; 3]) -> SurfacePoint {
+    let interpolate = |attribute: &[[f32; 3]]| {
+        (0..3).fold([0.0; 3], |sum, c| {
+            add(sum, scale(attribute[t[c] as usize], barycentrics[c]))
+        })
+    };
+
+    let p = |c: usize| input.positions[t[c] as usize];
+    let mut face_normal = normalize(cross(sub(p(1), p(0)), sub(p(2), p(0))));
+    let normal = if input.normals.is_empty() {
+        face_normal
+    } else {
+        let normal = normalize(interpolate(&input.normals));
+        if dot(normal, face_normal) < 0.0 {
+            face_normal = scale(face_normal, -1.0);
+        }
+        normal
+    };
+
+    SurfacePoint {
+        position: interpolate(&input.positions),
+        normal,
+        face_normal,
+    }
+}
+
+// World space positions and normals of the input meshes on `page`. Rasterization is conservative,
+// texels that only touch a triangle get the closest point on it. Texels outside of the charts are 0.
+pub fn gbuffer(atlas: &Xatlas, page: u32) -> (Image<[f32; 3]>, Image<[f32; 3]>) {
+    let meshes = atlas.meshes();
+    let size = [atlas.width(), atlas.height()];
+    let mut positions = Image::new(size[0], size[1]);
+    let mut normals = Image::new(size[0], size[1]);
+
+    for (i, texel) in sample_page(&meshes, page, size).iter().enumerate() {
+        if let Some(texel) = texel {
+            let point = surface_point(&meshes[texel.mesh], &atlas.inputs[texel.mesh], texel);
+            positions.data[i] = point.position;
+            normals.data[i] = point.normal;
+        }
+    }
+    (positions, normals)
+}
+
+// Ambient occlusion of every atlas page, 1 where the hemisphere around the surface normal is
+// fully open. All faces of the meshes added to `atlas` (including ignored ones) occlude. Rays are
+// cosine distributed and only count hits closer than `max_distance`, 0 for no limit. Without
+// samples every chart texel is open. Texels outside of the charts are left at 0.
+pub fn ambient_occlusion(atlas: &Xatlas, samples: u32, max_distance: f32) -> Vec<Image<f32>> {
+    let bvh = scene_bvh(&atlas.inputs);
+    let t_max = if max_distance > 0.0 {
+        max_distance
+    } else {
+        f32::INFINITY
+    };
+
+    let output_meshes = atlas.meshes();
+    let size = [atlas.width(), atlas.height()];
+    (0..atlas.atlas_count())
+        .map(|page| {
+            let mut image = Image::new(size[0], size[1]);
+            for (i, texel) in sample_page(&output_meshes, page, size).iter().enumerate() {
+                let texel = match texel {
+                    Some(texel) => texel,
+                    None => continue,
+                };
+                if samples == 0 {
+                    image.data[i] = 1.0;
+                    continue;
+                }
+                let input = &atlas.inputs[texel.mesh];
+                let point = surface_point(&output_meshes[texel.mesh], input, texel);
+                let origin = add(point.position, scale(point.face_normal, bvh.ray_offset()));
+                let open = sample_points(i as u32, samples)
+                    .filter(|&u| !bvh.occluded(origin, cosine_direction(point.normal, u), t_max))
+                    .count();
+                image.data[i] = open as f32 / samples as f32;
+            }
+            image
+        })
+        .collect()
+}
+
+// Every input triangle, mesh after mesh and in the order of `InputMesh::indices`
+pub(crate) fn scene_bvh(inputs: &[InputMesh]) -> Bvh {
+    let triangles: Vec<[Vec3; 3]> = inputs
+        .iter()
+        .flat_map(|input| {
+            input.indices.chunks_exact(3).map(move |t| {
+                [
+                    input.positions[t[0] as usize],
+                    input.positions[t[1] as usize],
+                    input.positions[t[2] as usize],
+                ]
+            })
+        })
+        .collect();
+    Bvh::new(&triangles)
+}
+
+// `samples` points in [0, 1)^2, a Hammersley set that every `seed` (texel) rotates differently,
+// trading banding for noise
+pub(crate) fn sample_points(seed: u32, samples: u32) -> impl Iterator<Item = [f32; 2]> {
+    let rotation = [hash(seed.wrapping_mul(2)), hash(seed.wrapping_mul(2) + 1)];
+    (0..samples).map(move |s| {
+        [
+            ((s as f32 + 0.5) / samples as f32 + rotation[0]).fract(),
+            (radical_inverse(s) + rotation[1]).fract(),
+        ]
+    })
+}
+
+// Maps a point of the unit square to the hemisphere around `normal`, cosine distributed
+pub(crate) fn cosine_direction(normal: Vec3, u: [f32; 2]) -> Vec3 {
+    let (tangent, bitangent) = tangent_frame(normal);
+    let r = u[0].sqrt();
+    let phi = u[1] * 2.0 * std::f32::consts::PI;
+    add(
+        add(
+            scale(tangent, r * phi.cos()),
+            scale(bitangent, r * phi.sin()),
+        ),
+        scale(normal, (1.0 - u[0]).max(0.0).sqrt()),
+    )
+}
+
+// Tangent space normal map of the input mesh `low` that captures the surface of `high`, one image
+// per atlas page. Rays start `cage_distance` above the low poly surface and look for `high` down
+// along the normal, up to `cage_distance` below it. Tangents are computed from the atlas uvs as
+// by `Mesh::compute_tangents`. Normals are encoded as `n * 0.5 + 0.5`, texels where the ray misses
+// get the low poly normal and texels outside of the charts of `low` are left at 0.
+pub fn normal_map(
+    atlas: &Xatlas,
+    low: usize,
+    high: &MeshDecl,
+    cage_distance: f32,
+) -> Result<Vec<Image<[f32; 3]>>, AddMeshError> {
+    let high = InputMesh::decode(high)?;
+    let bvh = scene_bvh(std::slice::from_ref(&high));
+
+    let input = &atlas.inputs[low];
+    let meshes = atlas.meshes();
+    let mesh = &meshes[low];
+    let normals = if input.normals.is_empty() {
+        vertex_normals(&input.positions, &input.indices)
+    } else {
+        input.normals.clone()
+    };
+    let tangents = mesh.compute_tangents(&input.positions, &normals);
+
+    let size = [atlas.width(), atlas.height()];
+    let images = (0..atlas.atlas_count())
+        .map(|page| {
+            let mut image = Image::new(size[0], size[1]);
+            let samples = sample_page(std::slice::from_ref(mesh), page, size);
+            for (i, texel) in samples.iter().enumerate() {
+                let texel = match texel {
+                    Some(texel) => texel,
+                    None => continue,
+                };
+                let t = &mesh.indices[texel.face * 3..texel.face * 3 + 3];
+                let mut position = [0.0; 3];
+                let mut n = [0.0; 3];
+                let mut tangent = [0.0; 3];
+                let corner_tangents = &tangents.indices[texel.face * 3..texel.face * 3 + 3];
+                for ((&v, &b), &corner) in t.iter().zip(&texel.barycentrics).zip(corner_tangents) {
+                    let xref = mesh.vertices[v as usize].xref as usize;
+                    let corner_tangent = tangents.tangents[corner as usize];
+                    position = add(position, scale(input.positions[xref], b));
+                    n = add(n, scale(normals[xref], b));
+                    tangent = add(
+                        tangent,
+                        scale([corner_tangent[0], corner_tangent[1], corner_tangent[2]], b),
+                    );
+                }
+                let n = normalize(n);
+                let tangent = normalize(sub(tangent, scale(n, dot(n, tangent))));
+                let sign = tangents.tangents[corner_tangents[0] as usize][3];
+                let bitangent = scale(cross(n, tangent), sign);
+
+                let origin = add(position, scale(n, cage_distance));
+                let dir = scale(n, -1.0);
+                let high_normal = match bvh.intersect(origin, dir, cage_distance * 2.0) {
+                    Some(hit) => {
+                        let h =
+                            &high.indices[hit.triangle as usize * 3..hit.triangle as usize * 3 + 3];
+                        input_point(&high, [h[0], h[1], h[2]], hit.barycentrics).normal
+                    }
+                    None => n,
+                };
+
+                let encode = |v: Vec3| dot(high_normal, v) * 0.5 + 0.5;
+                image.data[i] = [encode(tangent), encode(bitangent), encode(n)];
+            }
+            image
+        })
+        .collect();
+
+    Ok(images)
+}
+
+// Van der Corput sequence in base 2
+fn radical_inverse(i: u32) -> f32 {
+    i.reverse_bits() as f32 * (1.0 / 4_294_967_296.0)
+}
+
+// Integer hash to [0, 1)
+fn hash(mut x: u32) -> f32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    (x >> 8) as f32 * (1.0 / 16_777_216.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{decl, single_chart};
+    use crate::Vertex;
+
+    #[test]
+    fn chart_mask_covers_the_chart() {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let uvs = [[2.5, 2.5], [9.5, 2.5], [9.5, 9.5], [2.5, 9.5]];
+        let atlas = single_chart(positions, vec![0, 1, 2, 0, 2, 3], &uvs, 7.0);
+
+        let mask = chart_mask(&atlas, 0);
+        assert_eq!((mask.width, mask.height), (64, 64));
+        for y in 0..64 {
+            for x in 0..64 {
+                let inside = (2..10).contains(&x) && (2..10).contains(&y);
+                assert_eq!(mask.get(x, y), inside, "texel {} {}", x, y);
+            }
+        }
+        assert!(chart_mask(&atlas, 1).data.iter().all(|&m| !m));
+    }
+
+    #[test]
+    fn sample_is_bilinear_and_wraps() {
+        let mut image = Image::new(2, 2);
+        image.data = vec![0.0f32, 1.0, 2.0, 3.0];
+        assert_eq!(sample(&image, [0.25, 0.25]), 0.0);
+        assert_eq!(sample(&image, [0.75, 0.75]), 3.0);
+        assert_eq!(sample(&image, [0.5, 0.25]), 0.5);
+        assert_eq!(sample(&image, [0.5, 0.5]), 1.5);
+        // Halfway between the last and the first column
+        assert_eq!(sample(&image, [1.0, 0.25]), 0.5);
+        assert_eq!(sample(&image, [-0.75, 1.25]), 0.0);
+        assert_eq!(sample(&Image::<f32>::new(0, 0), [0.5, 0.5]), 0.0);
+    }
+
+    #[test]
+    fn transfer_of_an_identity_mapping_reproduces_the_source() {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let uvs = [[0.0, 0.0], [64.0, 0.0], [64.0, 64.0], [0.0, 64.0]];
+        let mut atlas = single_chart(positions, vec![0, 1, 2, 0, 2, 3], &uvs, 64.0);
+        // A vertex outside of every chart doesn't add pages
+        atlas.outputs[0].vertices.push(Vertex {
+            atlas_index: u32::MAX,
+            uv: [0.0, 0.0],
+            xref: 0,
+        });
+
+        let mut src = Image::new(64, 64);
+        for (i, texel) in src.data.iter_mut().enumerate() {
+            *texel = [(i % 64) as f32, (i / 64) as f32, 1.0];
+        }
+        let src_uvs: Vec<[f32; 2]> = uvs.iter().map(|uv| [uv[0] / 64.0, uv[1] / 64.0]).collect();
+
+        let images = transfer_texture(&src, &src_uvs, &atlas.meshes()[0], [64, 64]);
+        assert_eq!(images.len(), 1);
+        for (a, b) in images[0].data.iter().zip(&src.data) {
+            for c in 0..3 {
+                assert!((a[c] - b[c]).abs() < 1e-3, "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn gbuffer_interpolates_the_input() {
+        let positions = vec![
+            [0.0, 0.0, 2.0],
+            [1.0, 0.0, 2.0],
+            [1.0, 1.0, 2.0],
+            [0.0, 1.0, 2.0],
+        ];
+        let uvs = [[0.0, 0.0], [16.0, 0.0], [16.0, 16.0], [0.0, 16.0]];
+        let mut atlas = single_chart(positions, vec![0, 1, 2, 0, 2, 3], &uvs, 16.0);
+
+        let (positions, normals) = gbuffer(&atlas, 0);
+        let mask = chart_mask(&atlas, 0);
+        for y in 0..64 {
+            for x in 0..64 {
+                if x < 16 && y < 16 {
+                    let p = positions.get(x, y);
+                    let expected = [(x as f32 + 0.5) / 16.0, (y as f32 + 0.5) / 16.0, 2.0];
+                    assert!(length(sub(p, expected)) < 1e-5, "{:?} {:?}", p, expected);
+                    assert_eq!(normals.get(x, y), [0.0, 0.0, 1.0]);
+                } else if !mask.get(x, y) {
+                    assert_eq!(positions.get(x, y), [0.0; 3]);
+                    assert_eq!(normals.get(x, y), [0.0; 3]);
+                }
+            }
+        }
+
+        // Input normals are interpolated instead of taking the face normal
+        atlas.inputs[0].normals = vec![
+            [1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let (_, normals) = gbuffer(&atlas, 0);
+        let n = normals.get(8, 8);
+        assert!(length(sub(n, normalize([1.0, 1.0, 0.0]))) < 0.1, "{:?}", n);
+    }
+
+    #[test]
+    fn ambient_occlusion_of_an_open_and_a_covered_plane() {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let uvs = [[0.0, 0.0], [16.0, 0.0], [16.0, 16.0], [0.0, 16.0]];
+        let mut atlas = single_chart(positions, vec![0, 1, 2, 0, 2, 3], &uvs, 16.0);
+        let mask = chart_mask(&atlas, 0);
+
+        let open = ambient_occlusion(&atlas, 16, 0.0);
+        assert_eq!(open.len(), 1);
+        for (&ao, &covered) in open[0].data.iter().zip(&mask.data) {
+            assert_eq!(ao, if covered { 1.0 } else { 0.0 });
+        }
+
+        // A wide roof facing the plane, part of the input but not of any chart
+        let input = &mut atlas.inputs[0];
+        for &[x, y] in &[[-50.0, -50.0], [50.0, -50.0], [50.0, 50.0], [-50.0, 50.0]] {
+            input.positions.push([x, y, 0.5]);
+        }
+        input.indices.extend_from_slice(&[4, 6, 5, 4, 7, 6]);
+        let roofed = ambient_occlusion(&atlas, 16, 0.0);
+        for (&ao, &covered) in roofed[0].data.iter().zip(&mask.data) {
+            assert!(if covered { ao < 0.1 } else { ao == 0.0 }, "{}", ao);
+        }
+        let short = ambient_occlusion(&atlas, 16, 0.25);
+        assert_eq!(short[0].data, open[0].data);
+    }
+
+    #[test]
+    fn normal_map_of_a_tilted_high_poly_plane() {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let uvs = [[0.0, 0.0], [16.0, 0.0], [16.0, 16.0], [0.0, 16.0]];
+        let indices = vec![0, 1, 2, 0, 2, 3];
+        let mut atlas = single_chart(positions, indices.clone(), &uvs, 16.0);
+
+        // Rising along x, so its normal leans towards -x
+        let high: Vec<[f32; 3]> = [[-1.0, -1.0], [2.0, -1.0], [2.0, 2.0], [-1.0, 2.0]]
+            .iter()
+            .map(|&[x, y]| [x, y, 0.05 + x * 0.1])
+            .collect();
+        let n = normalize([-0.1, 0.0, 1.0]);
+        let check = |atlas: &Xatlas, high: &[[f32; 3]], expected: Vec3| {
+            let images = normal_map(atlas, 0, &decl(high, &indices), 0.5).unwrap();
+            assert_eq!(images.len(), 1);
+            for y in 0..16 {
+                for x in 0..16 {
+                    let texel = images[0].get(x, y);
+                    let decoded = [
+                        texel[0] * 2.0 - 1.0,
+                        texel[1] * 2.0 - 1.0,
+                        texel[2] * 2.0 - 1.0,
+                    ];
+                    assert!(
+                        length(sub(decoded, expected)) < 1e-3,
+                        "{:?} {:?}",
+                        decoded,
+                        expected
+                    );
+                }
+            }
+        };
+        check(&atlas, &high, n);
+
+        // A high poly surface out of reach leaves the low poly normal
+        let far: Vec<[f32; 3]> = high.iter().map(|p| [p[0], p[1], p[2] + 10.0]).collect();
+        check(&atlas, &far, [0.0, 0.0, 1.0]);
+
+        // Mirroring the uvs flips the tangent along with them
+        for v in atlas.outputs[0].vertices.iter_mut() {
+            v.uv[0] = 16.0 - v.uv[0];
+        }
+        check(&atlas, &high, [-n[0], n[1], n[2]]);
+    }
+
+    #[test]
+    fn stitched_seam_texels_match_on_both_sides() {
+        // The diagonal of a quad cut into two charts side by side
+        let positions = vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]];
+        let positions = [positions.clone(), positions].concat();
+        let uvs = [
+            [2.0, 2.0],
+            [10.0, 2.0],
+            [10.0, 10.0],
+            [18.0, 2.0],
+            [26.0, 10.0],
+            [18.0, 10.0],
+        ];
+        let mut atlas = single_chart(positions, vec![0, 1, 2, 3, 4, 5], &uvs, 8.0);
+        for (v, xref) in [(3, 0), (4, 2), (5, 3)].iter() {
+            atlas.outputs[0].vertices[*v].xref = *xref;
+        }
+
+        let mut image = Image::new(32, 16);
+        for (i, texel) in image.data.iter_mut().enumerate() {
+            *texel = if i % 32 < 16 { 0.0f32 } else { 1.0 };
+        }
+        let seam = |image: &Image<f32>, t: f32| {
+            let uv = |a: [f32; 2], b: [f32; 2]| {
+                let p = [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t];
+                sample(image, [p[0] / 32.0, p[1] / 16.0])
+            };
+            (uv(uvs[0], uvs[2]), uv(uvs[3], uvs[4]))
+        };
+        let (a, b) = seam(&image, 0.5);
+        assert_eq!(b - a, 1.0);
+
+        stitch_seams(&mut image, &atlas.meshes()[0], 0);
+        // Seams are matched at samples along their length, short of the corners
+        for s in 1..10 {
+            let (a, b) = seam(&image, s as f32 / 10.0);
+            assert!((a - b).abs() < 0.1, "{} {}", a, b);
+        }
+        // Texels away from the seam keep their values
+        assert_eq!(image.get(0, 15), 0.0);
+        assert_eq!(image.get(31, 0), 1.0);
+
+        // Nothing to do on other pages
+        let before = image.clone();
+        stitch_seams(&mut image, &atlas.meshes()[0], 1);
+        assert_eq!(image.data, before.data);
+    }
+
+    #[test]
+    fn dilate_fills_one_ring_per_iteration() {
+        let mut mask = Image::new(9, 9);
+        mask.set(4, 4, true);
+        let mut image = Image::new(9, 9);
+        image.data = vec![-1.0f32; 81];
+        image.set(4, 4, 2.0);
+
+        for iterations in 1..=3 {
+            let mut dilated = image.clone();
+            dilate(&mut dilated, &mask, iterations);
+            for y in 0..9 {
+                for x in 0..9 {
+                    let ring = (x as i32 - 4).abs().max((y as i32 - 4).abs()) as u32;
+                    let expected = if ring <= iterations { 2.0 } else { -1.0 };
+                    assert_eq!(dilated.get(x, y), expected, "texel {} {}", x, y);
+                }
+            }
+        }
+
+        // Gutter texels take the average of their valid neighbours
+        mask.set(6, 4, true);
+        image.set(6, 4, 4.0);
+        dilate(&mut image, &mask, 1);
+        assert_eq!(image.get(5, 4), 3.0);
+        assert_eq!(image.get(3, 4), 2.0);
+        assert_eq!(image.get(7, 4), 4.0);
+    }
+}
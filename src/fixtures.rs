@@ -0,0 +1,82 @@
+// Meshes and atlases shared by the unit tests
+
+use crate::input::{bytes, InputMesh};
+use crate::{ChartOutput, IndexFormat, MeshDecl, MeshOutput, Vertex, Xatlas};
+use std::mem::ManuallyDrop;
+
+// A triangle mesh with positions only
+pub(crate) fn decl<'a>(positions: &'a [[f32; 3]], indices: &'a [u32]) -> MeshDecl<'a> {
+    MeshDecl {
+        vertex_count: positions.len() as u32,
+        vertex_position_data: bytes(positions),
+        vertex_position_stride: 12,
+        index_count: indices.len() as u32,
+        index_data: bytes(indices),
+        index_format: IndexFormat::Uint32,
+        ..Default::default()
+    }
+}
+
+// `n` by `n` quads over the unit square, lifted by `height`
+pub(crate) fn grid(n: u32, height: impl Fn(f32, f32) -> f32) -> (Vec<[f32; 3]>, Vec<u32>) {
+    let mut positions = vec![];
+    for y in 0..=n {
+        for x in 0..=n {
+            let (u, v) = (x as f32 / n as f32, y as f32 / n as f32);
+            positions.push([u, v, height(u, v)]);
+        }
+    }
+    let mut indices = vec![];
+    for y in 0..n {
+        for x in 0..n {
+            let a = y * (n + 1) + x;
+            indices.extend_from_slice(&[a, a + 1, a + n + 2, a, a + n + 2, a + n + 1]);
+        }
+    }
+    (positions, indices)
+}
+
+// An atlas holding a single chart with the given uvs, without an xatlas handle
+pub(crate) fn single_chart(
+    positions: Vec<[f32; 3]>,
+    indices: Vec<u32>,
+    uvs: &[[f32; 2]],
+    texels_per_unit: f32,
+) -> ManuallyDrop<Xatlas> {
+    let vertices = uvs
+        .iter()
+        .enumerate()
+        .map(|(i, &uv)| Vertex {
+            atlas_index: 0,
+            uv,
+            xref: i as u32,
+        })
+        .collect();
+    let output = MeshOutput {
+        charts: vec![ChartOutput {
+            atlas_index: 0,
+            indices: indices.clone(),
+            texels_per_unit,
+        }],
+        original_face: (0..indices.len() as u32 / 3).collect(),
+        indices: indices.clone(),
+        vertices,
+        texels_per_unit,
+    };
+    let input = InputMesh {
+        positions,
+        indices,
+        importance: 1.0,
+        ..Default::default()
+    };
+    ManuallyDrop::new(Xatlas {
+        handle: std::ptr::null_mut(),
+        inputs: vec![input],
+        outputs: vec![output],
+        width: 64,
+        height: 64,
+        atlas_count: 1,
+        utilization: vec![],
+        texels_per_unit,
+    })
+}
@@ -0,0 +1,903 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+use crate::projection::Projection;
+use crate::{AddMeshError, IndexFormat, MeshDecl};
+
+// Decoded copy of a MeshDecl; xatlas only ever sees the parts built from this.
+#[derive(Debug, Default)]
+pub(crate) struct InputMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+    pub indices: Vec<u32>,
+    pub face_polygon: Vec<u32>, // triangle -> input face, empty when the input was triangles
+    pub face_ignore: Vec<bool>,
+    pub face_importance: Vec<f32>,
+    pub face_group: Vec<u32>,
+    pub face_seam_region: Vec<u32>,
+    pub face_projection: Vec<u32>, // projection key of every triangle
+    pub importance: f32,
+    pub projection: Option<Projection>,
+    pub parts: Vec<Part>,
+}
+
+// A subset of the faces of an InputMesh that is handed to xatlas as a separate mesh,
+// faces in different parts never end up in the same chart.
+#[derive(Debug, Default)]
+pub(crate) struct Part {
+    pub importance: f32,
+    pub projection_key: Option<u32>, // the part is flattened with this projection
+    pub vertices: Vec<u32>,          // part vertex -> input vertex
+    pub faces: Vec<u32>,             // part face -> input face
+    pub indices: Vec<u32>,
+}
+
+// Output triangles only reference vertices, so the part face they were created from is
+// looked up by its vertex indices. Faces are keyed starting at their lowest index to be
+// independent of which corner xatlas starts a triangle with.
+pub(crate) struct FaceLookup(HashMap<[u32; 3], Vec<u32>>);
+
+fn triangle_key(t: [u32; 3]) -> [u32; 3] {
+    if t[0] <= t[1] && t[0] <= t[2] {
+        t
+    } else if t[1] <= t[2] {
+        [t[1], t[2], t[0]]
+    } else {
+        [t[2], t[0], t[1]]
+    }
+}
+
+impl FaceLookup {
+    // Ignored faces are not part of the output and skipped so they can't shadow duplicates
+    pub fn new(input: &InputMesh, part: &Part) -> Self {
+        let mut faces = HashMap::new();
+        for (face, t) in part.indices.chunks_exact(3).enumerate().rev() {
+            if input.face_ignore(part.faces[face] as usize) {
+                continue;
+            }
+            faces
+                .entry(triangle_key([t[0], t[1], t[2]]))
+                .or_insert_with(Vec::new)
+                .push(part.faces[face]);
+        }
+        FaceLookup(faces)
+    }
+
+    // Returns the input face, duplicate faces are handed out in order
+    pub fn take(&mut self, t: [u32; 3]) -> Option<u32> {
+        self.0
+            .get_mut(&triangle_key(t))
+            .and_then(|faces| faces.pop())
+    }
+}
+
+fn read_f32s<'a>(
+    data: &'a [u8],
+    stride: u32,
+    count: u32,
+    components: usize,
+) -> Result<impl Iterator<Item = [f32; 3]> + 'a, AddMeshError> {
+    let stride = stride as usize;
+    let size = components * std::mem::size_of::<f32>();
+
+    if count > 0 && (stride < size || (count as usize - 1) * stride + size > data.len()) {
+        return Err(AddMeshError::InvalidVertexData);
+    }
+
+    Ok((0..count as usize).map(move |i| {
+        let mut v = [0.0; 3];
+        for (c, v) in v.iter_mut().enumerate().take(components) {
+            let offset = i * stride + c * 4;
+            *v = f32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+        }
+        v
+    }))
+}
+
+impl<'a> MeshDecl<'a> {
+    pub(crate) fn decode_positions(&self) -> Result<Vec<[f32; 3]>, AddMeshError> {
+        let positions: Vec<[f32; 3]> = read_f32s(
+            self.vertex_position_data,
+            self.vertex_position_stride,
+            self.vertex_count,
+            3,
+        )?
+        .collect();
+        if positions.iter().flatten().any(|c| !c.is_finite()) {
+            return Err(AddMeshError::InvalidVertexData);
+        }
+        Ok(positions)
+    }
+
+    pub(crate) fn decode_normals(&self) -> Result<Vec<[f32; 3]>, AddMeshError> {
+        if self.vertex_normal_data.is_empty() {
+            return Ok(vec![]);
+        }
+
+        Ok(read_f32s(
+            self.vertex_normal_data,
+            self.vertex_normal_stride,
+            self.vertex_count,
+            3,
+        )?
+        .collect())
+    }
+
+    pub(crate) fn decode_uvs(&self) -> Result<Vec<[f32; 2]>, AddMeshError> {
+        if self.vertex_uv_data.is_empty() {
+            return Ok(vec![]);
+        }
+
+        Ok(read_f32s(
+            self.vertex_uv_data,
+            self.vertex_uv_stride,
+            self.vertex_count,
+            2,
+        )?
+        .map(|v| [v[0], v[1]])
+        .collect())
+    }
+
+    // Number of vertices of every face in the decoded `indices`
+    pub(crate) fn decode_face_sizes(&self, indices: &[u32]) -> Result<Vec<usize>, AddMeshError> {
+        if self.face_vertex_counts.is_empty() {
+            if !indices.chunks_exact(3).remainder().is_empty() {
+                return Err(AddMeshError::InvalidIndexCount);
+            }
+            return Ok(vec![3; indices.len() / 3]);
+        }
+
+        let sizes: Vec<usize> = self
+            .face_vertex_counts
+            .iter()
+            .map(|&c| c as usize)
+            .collect();
+        if sizes.iter().any(|&c| c < 3) || sizes.iter().sum::<usize>() != indices.len() {
+            return Err(AddMeshError::InvalidFaceVertexCount);
+        }
+        Ok(sizes)
+    }
+
+    // Indices with index_offset applied, an empty index_data means the vertices form a face list.
+    pub(crate) fn decode_indices(&self) -> Result<Vec<u32>, AddMeshError> {
+        let indices: Vec<i64> = if self.index_data.is_empty() {
+            let count = if self.index_count > 0 {
+                self.index_count
+            } else {
+                self.vertex_count
+            };
+            (0..count as i64).collect()
+        } else {
+            let size = match self.index_format {
+                IndexFormat::Uint16 => 2,
+                IndexFormat::Uint32 => 4,
+            };
+
+            if self.index_data.len() < self.index_count as usize * size {
+                return Err(AddMeshError::InvalidIndexCount);
+            }
+
+            self.index_data
+                .chunks_exact(size)
+                .take(self.index_count as usize)
+                .map(|b| match self.index_format {
+                    IndexFormat::Uint16 => i64::from(u16::from_ne_bytes(b.try_into().unwrap())),
+                    IndexFormat::Uint32 => i64::from(u32::from_ne_bytes(b.try_into().unwrap())),
+                })
+                .collect()
+        };
+
+        indices
+            .into_iter()
+            .map(|i| {
+                let i = i + i64::from(self.index_offset);
+                if i < 0 || i >= i64::from(self.vertex_count) {
+                    Err(AddMeshError::IndexOutOfRange)
+                } else {
+                    Ok(i as u32)
+                }
+            })
+            .collect()
+    }
+}
+
+impl InputMesh {
+    pub fn decode(decl: &MeshDecl) -> Result<Self, AddMeshError> {
+        let face_indices = decl.decode_indices()?;
+        let positions = decl.decode_positions()?;
+        let face_sizes = decl.decode_face_sizes(&face_indices)?;
+        let face_count = face_sizes.len();
+
+        if !decl.face_ignore_data.is_empty() && decl.face_ignore_data.len() != face_count {
+            return Err(AddMeshError::InvalidFaceData);
+        }
+        if !decl.face_importance_data.is_empty() && decl.face_importance_data.len() != face_count {
+            return Err(AddMeshError::InvalidFaceData);
+        }
+        if !decl.face_group_data.is_empty() && decl.face_group_data.len() != face_count {
+            return Err(AddMeshError::InvalidFaceData);
+        }
+        let valid_importance = |i: &f32| i.is_finite() && *i > 0.0;
+        if !valid_importance(&decl.importance)
+            || !decl.face_importance_data.iter().all(valid_importance)
+        {
+            return Err(AddMeshError::InvalidImportance);
+        }
+
+        let mut indices = vec![];
+        let mut face_polygon = vec![];
+        let mut edges = HashSet::new();
+        let mut first = 0;
+        for (face, &size) in face_sizes.iter().enumerate() {
+            let polygon = &face_indices[first..first + size];
+            first += size;
+
+            for i in 0..size {
+                edges.insert(edge_key(polygon[i], polygon[(i + 1) % size]));
+            }
+
+            if decl.face_vertex_counts.is_empty() {
+                indices.extend_from_slice(polygon);
+            } else {
+                indices.extend(triangulate(&positions, polygon));
+                face_polygon.resize(indices.len() / 3, face as u32);
+            }
+        }
+
+        let mut mesh = InputMesh {
+            positions,
+            normals: decl.decode_normals()?,
+            uvs: decl.decode_uvs()?,
+            indices,
+            face_polygon,
+            face_ignore: decl.face_ignore_data.to_vec(),
+            face_importance: decl.face_importance_data.to_vec(),
+            face_group: decl.face_group_data.to_vec(),
+            face_seam_region: vec![],
+            face_projection: vec![],
+            importance: decl.importance,
+            projection: None,
+            parts: vec![],
+        };
+
+        if let Some(mode) = decl.projection {
+            let projection = Projection::new(mode, &mesh.positions, &mesh.indices);
+            mesh.face_projection = mesh
+                .indices
+                .chunks_exact(3)
+                .map(|t| projection.face_key(&mesh.positions, t))
+                .collect();
+            mesh.projection = Some(projection);
+        }
+
+        if !decl.seam_edge_data.is_empty() || mesh.projection.is_some() {
+            mesh.build_seam_regions(decl.seam_edge_data, &edges)?;
+        }
+        mesh.build_parts();
+        Ok(mesh)
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // The face as it was declared, which differs from the triangle index for polygon input
+    pub fn input_face(&self, face: usize) -> usize {
+        self.face_polygon
+            .get(face)
+            .map(|&f| f as usize)
+            .unwrap_or(face)
+    }
+
+    pub fn face_ignore(&self, face: usize) -> bool {
+        let face = self.input_face(face);
+        self.face_ignore.get(face).cloned().unwrap_or(false)
+    }
+
+    pub fn face_importance(&self, face: usize) -> f32 {
+        let face = self.input_face(face);
+        let importance = self.face_importance.get(face).cloned().unwrap_or(1.0);
+        importance * self.importance
+    }
+
+    pub fn face_group(&self, face: usize) -> u32 {
+        let face = self.input_face(face);
+        self.face_group.get(face).cloned().unwrap_or(0)
+    }
+
+    pub fn face_seam_region(&self, face: usize) -> u32 {
+        self.face_seam_region.get(face).cloned().unwrap_or(0)
+    }
+
+    pub fn face_projection(&self, face: usize) -> Option<u32> {
+        self.face_projection.get(face).cloned()
+    }
+
+    // Vertex ids where vertices with identical positions are merged, xatlas connects
+    // faces across such split vertices too.
+    fn welded_vertices(&self) -> Vec<u32> {
+        let mut lookup = HashMap::new();
+        self.positions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let key = [p[0].to_bits(), p[1].to_bits(), p[2].to_bits()];
+                *lookup.entry(key).or_insert(i as u32)
+            })
+            .collect()
+    }
+
+    // Label faces by the region they belong to when the surface is cut along the seam edges,
+    // and between faces with a different projection. Seams that don't cut their region apart
+    // (an open cut line on a cylinder) are honoured by splitting the region further.
+    fn build_seam_regions(
+        &mut self,
+        seams: &[[u32; 2]],
+        edges: &HashSet<(u32, u32)>,
+    ) -> Result<(), AddMeshError> {
+        let welded = self.welded_vertices();
+        let mut seam_edges = HashSet::new();
+        for seam in seams {
+            if seam[0] as usize >= self.positions.len() || seam[1] as usize >= self.positions.len()
+            {
+                return Err(AddMeshError::IndexOutOfRange);
+            }
+            if !edges.contains(&edge_key(seam[0], seam[1])) {
+                return Err(AddMeshError::InvalidSeamEdge);
+            }
+            seam_edges.insert(edge_key(welded[seam[0] as usize], welded[seam[1] as usize]));
+        }
+
+        let mut edge_faces: HashMap<(u32, u32), Vec<u32>> = HashMap::new();
+        for (face, indices) in self.indices.chunks_exact(3).enumerate() {
+            for i in 0..3 {
+                let edge = edge_key(
+                    welded[indices[i] as usize],
+                    welded[indices[(i + 1) % 3] as usize],
+                );
+                edge_faces.entry(edge).or_default().push(face as u32);
+            }
+        }
+
+        // Faces that may share a chart, across an edge that is no seam and between faces
+        // with the same projection
+        let mut neighbours = vec![vec![]; self.face_count()];
+        for (edge, faces) in &edge_faces {
+            if seam_edges.contains(edge) {
+                continue;
+            }
+            for (i, &a) in faces.iter().enumerate() {
+                for &b in &faces[i + 1..] {
+                    if a != b
+                        && self.face_projection(a as usize) == self.face_projection(b as usize)
+                    {
+                        neighbours[a as usize].push(b);
+                        neighbours[b as usize].push(a);
+                    }
+                }
+            }
+        }
+
+        // Sorted, the splits below depend on the order faces are visited in
+        for faces in &mut neighbours {
+            faces.sort_unstable();
+        }
+
+        let mut regions = UnionFind::new(self.face_count());
+        for (a, faces) in neighbours.iter().enumerate() {
+            for &b in faces {
+                regions.union(a as u32, b);
+            }
+        }
+
+        // Pairs of faces across a seam that ended up in the same region anyway
+        let mut opposed = vec![];
+        for edge in &seam_edges {
+            let faces = &edge_faces[edge];
+            for (i, &a) in faces.iter().enumerate() {
+                for &b in &faces[i + 1..] {
+                    if a != b && regions.find(a) == regions.find(b) {
+                        opposed.push((a, b));
+                    }
+                }
+            }
+        }
+        opposed.sort_unstable();
+
+        self.face_seam_region = (0..self.face_count() as u32)
+            .map(|face| regions.find(face))
+            .collect();
+        if !opposed.is_empty() {
+            self.split_regions(&neighbours, &opposed);
+        }
+        Ok(())
+    }
+
+    // Every face along an uncut seam grows a patch of its region, breadth first so the patches
+    // stay compact. Patches are then merged closest to the seam first, unless that would put
+    // both sides of a seam edge together, which leaves the extra boundaries far from the seam.
+    fn split_regions(&mut self, neighbours: &[Vec<u32>], opposed: &[(u32, u32)]) {
+        let mut patch = vec![u32::MAX; self.face_count()];
+        let mut distance = vec![0u32; self.face_count()];
+        let mut queue = std::collections::VecDeque::new();
+        for &(a, b) in opposed {
+            for face in [a, b] {
+                if patch[face as usize] == u32::MAX {
+                    patch[face as usize] = face;
+                    queue.push_back(face);
+                }
+            }
+        }
+        while let Some(face) = queue.pop_front() {
+            for &other in &neighbours[face as usize] {
+                if patch[other as usize] == u32::MAX {
+                    patch[other as usize] = patch[face as usize];
+                    distance[other as usize] = distance[face as usize] + 1;
+                    queue.push_back(other);
+                }
+            }
+        }
+
+        let mut merges = vec![];
+        for (a, faces) in neighbours.iter().enumerate() {
+            for &b in faces {
+                if patch[a] != u32::MAX && patch[a] != patch[b as usize] {
+                    let d = distance[a].max(distance[b as usize]);
+                    merges.push((d, patch[a], patch[b as usize]));
+                }
+            }
+        }
+        merges.sort_unstable();
+
+        let mut patches = UnionFind::new(self.face_count());
+        let mut separate: HashMap<u32, HashSet<u32>> = HashMap::new();
+        for &(a, b) in opposed {
+            let (a, b) = (patch[a as usize], patch[b as usize]);
+            separate.entry(a).or_default().insert(b);
+            separate.entry(b).or_default().insert(a);
+        }
+        for (_, a, b) in merges {
+            let (a, b) = (patches.find(a), patches.find(b));
+            if a == b || separate.get(&a).is_some_and(|s| s.contains(&b)) {
+                continue;
+            }
+            patches.union(a, b);
+            // The root of the merged patch inherits the seams of both
+            let root = patches.find(a);
+            let mut merged: HashSet<u32> = HashSet::new();
+            for p in [a, b] {
+                merged.extend(separate.remove(&p).unwrap_or_default());
+            }
+            for &other in &merged {
+                let s = separate.get_mut(&other).unwrap();
+                s.remove(&a);
+                s.remove(&b);
+                s.insert(root);
+            }
+            separate.insert(root, merged);
+        }
+
+        // Patches are labeled by one of their faces, which no other region uses
+        for (face, &patch) in patch.iter().enumerate() {
+            if patch != u32::MAX {
+                self.face_seam_region[face] = patches.find(patch);
+            }
+        }
+    }
+
+    // Faces with differing importance are scaled differently and faces in different groups
+    // or seam regions must not share a chart, so all of those have to be charted separately.
+    fn build_parts(&mut self) {
+        let mut part_lookup = HashMap::new();
+        let mut vertex_maps: Vec<HashMap<u32, u32>> = vec![];
+
+        for face in 0..self.face_count() {
+            let importance = self.face_importance(face);
+            let projection_key = self.face_projection(face);
+            let key = (
+                self.face_group(face),
+                self.face_seam_region(face),
+                importance.to_bits(),
+                projection_key,
+            );
+            let part = *part_lookup.entry(key).or_insert_with(|| {
+                self.parts.push(Part {
+                    importance,
+                    projection_key,
+                    ..Default::default()
+                });
+                vertex_maps.push(HashMap::new());
+                self.parts.len() - 1
+            });
+
+            let Part {
+                vertices, indices, ..
+            } = &mut self.parts[part];
+            for &v in &self.indices[face * 3..face * 3 + 3] {
+                let local = *vertex_maps[part].entry(v).or_insert_with(|| {
+                    vertices.push(v);
+                    vertices.len() as u32 - 1
+                });
+                indices.push(local);
+            }
+            self.parts[part].faces.push(face as u32);
+        }
+    }
+
+    // Tightly packed vertex data for a part, positions are scaled by the part's importance.
+    // Projected parts are flattened so xatlas sees a single planar chart, their normals and
+    // uvs are left out to keep those from splitting it.
+    pub fn part_positions(&self, part: &Part) -> Vec<[f32; 3]> {
+        let scale = part.importance;
+        part.vertices
+            .iter()
+            .map(|&v| {
+                let p = self.positions[v as usize];
+                match (&self.projection, part.projection_key) {
+                    (Some(projection), Some(key)) => {
+                        let uv = projection.project(key, p);
+                        [uv[0] * scale, uv[1] * scale, 0.0]
+                    }
+                    _ => [p[0] * scale, p[1] * scale, p[2] * scale],
+                }
+            })
+            .collect()
+    }
+
+    pub fn part_normals(&self, part: &Part) -> Vec<[f32; 3]> {
+        if self.normals.is_empty() || part.projection_key.is_some() {
+            return vec![];
+        }
+        part.vertices
+            .iter()
+            .map(|&v| self.normals[v as usize])
+            .collect()
+    }
+
+    pub fn part_uvs(&self, part: &Part) -> Vec<[f32; 2]> {
+        if self.uvs.is_empty() || part.projection_key.is_some() {
+            return vec![];
+        }
+        part.vertices
+            .iter()
+            .map(|&v| self.uvs[v as usize])
+            .collect()
+    }
+
+    pub fn part_face_ignore(&self, part: &Part) -> Vec<bool> {
+        if self.face_ignore.is_empty() {
+            return vec![];
+        }
+        part.faces
+            .iter()
+            .map(|&f| self.face_ignore(f as usize))
+            .collect()
+    }
+}
+
+pub(crate) struct UnionFind {
+    parents: Vec<u32>,
+}
+
+impl UnionFind {
+    pub fn new(count: usize) -> Self {
+        UnionFind {
+            parents: (0..count as u32).collect(),
+        }
+    }
+
+    pub fn find(&mut self, mut i: u32) -> u32 {
+        while self.parents[i as usize] != i {
+            self.parents[i as usize] = self.parents[self.parents[i as usize] as usize];
+            i = self.parents[i as usize];
+        }
+        i
+    }
+
+    pub fn union(&mut self, a: u32, b: u32) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a as usize] = b;
+    }
+}
+
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+// Ear clipping in the plane of the polygon, this always produces the same triangles for the
+// same polygon and handles concave faces. Degenerate polygons fall back to a fan.
+pub(crate) fn triangulate(positions: &[[f32; 3]], polygon: &[u32]) -> Vec<u32> {
+    if polygon.len() == 3 {
+        return polygon.to_vec();
+    }
+
+    // Newell normal, project along its dominant axis
+    let mut normal = [0.0f32; 3];
+    for i in 0..polygon.len() {
+        let a = positions[polygon[i] as usize];
+        let b = positions[polygon[(i + 1) % polygon.len()] as usize];
+        normal[0] += (a[1] - b[1]) * (a[2] + b[2]);
+        normal[1] += (a[2] - b[2]) * (a[0] + b[0]);
+        normal[2] += (a[0] - b[0]) * (a[1] + b[1]);
+    }
+    let axis = (0..3)
+        .max_by(|&a, &b| normal[a].abs().total_cmp(&normal[b].abs()))
+        .unwrap();
+    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+    let sign = if normal[axis] < 0.0 { -1.0 } else { 1.0 };
+    let points: Vec<[f32; 2]> = polygon
+        .iter()
+        .map(|&i| {
+            let p = positions[i as usize];
+            [p[u], p[v] * sign]
+        })
+        .collect();
+
+    let cross = |a: [f32; 2], b: [f32; 2], c: [f32; 2]| {
+        (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+    };
+
+    let mut remaining: Vec<usize> = (0..polygon.len()).collect();
+    let mut triangles = vec![];
+
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let ear = (0..n).find(|&i| {
+            let (a, b, c) = (
+                remaining[(i + n - 1) % n],
+                remaining[i],
+                remaining[(i + 1) % n],
+            );
+            if cross(points[a], points[b], points[c]) <= 0.0 {
+                return false;
+            }
+            remaining.iter().all(|&p| {
+                p == a
+                    || p == b
+                    || p == c
+                    || cross(points[a], points[b], points[p]) < 0.0
+                    || cross(points[b], points[c], points[p]) < 0.0
+                    || cross(points[c], points[a], points[p]) < 0.0
+            })
+        });
+
+        let i = match ear {
+            Some(i) => i,
+            None => break,
+        };
+        triangles.extend_from_slice(&[
+            polygon[remaining[(i + n - 1) % n]],
+            polygon[remaining[i]],
+            polygon[remaining[(i + 1) % n]],
+        ]);
+        remaining.remove(i);
+    }
+
+    for i in 1..remaining.len() - 1 {
+        triangles.extend_from_slice(&[
+            polygon[remaining[0]],
+            polygon[remaining[i]],
+            polygon[remaining[i + 1]],
+        ]);
+    }
+
+    triangles
+}
+
+// Raw bytes of plain vertex and index data, for building a MeshDecl
+pub(crate) fn bytes<T: Copy>(data: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::decl;
+    use crate::math::scale;
+
+    // Open tube of `segments` quads around the z axis, vertex `i` at the bottom and `i + segments`
+    // at the top
+    fn tube(segments: u32) -> (Vec<[f32; 3]>, Vec<u32>) {
+        let mut positions = vec![];
+        for z in 0..2 {
+            for i in 0..segments {
+                let a = i as f32 / segments as f32 * std::f32::consts::TAU;
+                positions.push([a.cos(), a.sin(), z as f32]);
+            }
+        }
+        let mut indices = vec![];
+        for i in 0..segments {
+            let j = (i + 1) % segments;
+            indices.extend_from_slice(&[i, j, j + segments, i, j + segments, i + segments]);
+        }
+        (positions, indices)
+    }
+
+    #[test]
+    fn face_importance_splits_parts() {
+        let (positions, indices) = tube(4);
+        let importance = [1.0, 1.0, 2.0, 2.0, 1.0, 1.0, 2.0, 2.0];
+        let mesh = InputMesh::decode(&MeshDecl {
+            importance: 0.5,
+            face_importance_data: &importance,
+            ..decl(&positions, &indices)
+        })
+        .unwrap();
+
+        assert_eq!(mesh.parts.len(), 2);
+        for part in &mesh.parts {
+            let expected: Vec<u32> = (0..8)
+                .filter(|&f| importance[f as usize] * 0.5 == part.importance)
+                .collect();
+            assert_eq!(part.faces, expected);
+
+            // Positions are scaled by the combined importance of the mesh and its faces
+            let scaled = mesh.part_positions(part);
+            for (p, &v) in scaled.iter().zip(&part.vertices) {
+                assert_eq!(*p, scale(positions[v as usize], part.importance));
+            }
+        }
+        let importances: Vec<f32> = mesh.parts.iter().map(|part| part.importance).collect();
+        assert_eq!(importances, vec![0.5, 1.0]);
+    }
+
+    #[test]
+    fn face_groups_split_parts() {
+        let (positions, indices) = tube(4);
+        let groups = [0, 0, 7, 7, 0, 0, 7, 7];
+        let mesh = InputMesh::decode(&MeshDecl {
+            face_group_data: &groups,
+            ..decl(&positions, &indices)
+        })
+        .unwrap();
+        let faces: Vec<&[u32]> = mesh.parts.iter().map(|part| &part.faces[..]).collect();
+        assert_eq!(faces, vec![&[0, 1, 4, 5][..], &[2, 3, 6, 7][..]]);
+
+        // Parts are split by both groups and importance
+        let importance = [1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 1.0, 2.0];
+        let mesh = InputMesh::decode(&MeshDecl {
+            face_group_data: &groups,
+            face_importance_data: &importance,
+            ..decl(&positions, &indices)
+        })
+        .unwrap();
+        let faces: Vec<&[u32]> = mesh.parts.iter().map(|part| &part.faces[..]).collect();
+        assert_eq!(
+            faces,
+            vec![&[0, 4][..], &[1, 5][..], &[2, 6][..], &[3, 7][..]]
+        );
+    }
+
+    #[test]
+    fn closed_seams_split_regions() {
+        let (positions, indices) = tube(8);
+        let seams = [[0, 8], [4, 12]];
+        let mesh = InputMesh::decode(&MeshDecl {
+            seam_edge_data: &seams,
+            ..decl(&positions, &indices)
+        })
+        .unwrap();
+        let regions: HashSet<u32> = mesh.face_seam_region.iter().cloned().collect();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(mesh.parts.len(), 2);
+    }
+
+    #[test]
+    fn open_seams_are_cut() {
+        let (positions, indices) = tube(8);
+        let seams = [[0, 8]];
+        let mesh = InputMesh::decode(&MeshDecl {
+            seam_edge_data: &seams,
+            ..decl(&positions, &indices)
+        })
+        .unwrap();
+
+        // Quad 7 ends and quad 0 starts at the seam
+        let (before, after) = (mesh.face_seam_region[14], mesh.face_seam_region[0]);
+        assert_ne!(before, after);
+        let regions: HashSet<u32> = mesh.face_seam_region.iter().cloned().collect();
+        assert_eq!(regions.len(), 2);
+
+        // Every region is connected
+        for &region in &regions {
+            let faces: Vec<usize> = (0..mesh.face_count())
+                .filter(|&f| mesh.face_seam_region[f] == region)
+                .collect();
+            let quads: HashSet<usize> = faces.iter().map(|&f| f / 2).collect();
+            let gaps = (0..8).filter(|q| quads.contains(q) && !quads.contains(&((q + 1) % 8)));
+            assert_eq!(gaps.count(), 1);
+        }
+    }
+
+    #[test]
+    fn seams_must_be_edges() {
+        let (positions, indices) = tube(8);
+        let seams = [[0, 12]];
+        let error = InputMesh::decode(&MeshDecl {
+            seam_edge_data: &seams,
+            ..decl(&positions, &indices)
+        });
+        assert_eq!(error.err(), Some(AddMeshError::InvalidSeamEdge));
+    }
+
+    #[test]
+    fn polygons_are_triangulated() {
+        // Concave L shape, the ear at the reflex corner must not be clipped
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [2.0, 0.0, 0.0],
+            [2.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [1.0, 2.0, 0.0],
+            [0.0, 2.0, 0.0],
+        ];
+        let triangles = triangulate(&positions, &[0, 1, 2, 3, 4, 5]);
+        assert_eq!(triangles.len(), 12);
+        let area: f32 = triangles
+            .chunks_exact(3)
+            .map(|t| {
+                let p = |c: usize| positions[t[c] as usize];
+                let (a, b) = (
+                    [p(1)[0] - p(0)[0], p(1)[1] - p(0)[1]],
+                    [p(2)[0] - p(0)[0], p(2)[1] - p(0)[1]],
+                );
+                let area = (a[0] * b[1] - a[1] * b[0]) * 0.5;
+                assert!(area > 0.0);
+                area
+            })
+            .sum();
+        assert!((area - 3.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn invalid_polygons_and_positions() {
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let indices = [0, 1, 2, 3];
+        let counts = [3];
+        let error = InputMesh::decode(&MeshDecl {
+            face_vertex_counts: &counts,
+            ..decl(&positions, &indices)
+        });
+        assert_eq!(error.err(), Some(AddMeshError::InvalidFaceVertexCount));
+
+        let counts = [4];
+        let mut positions = positions;
+        positions[2][1] = f32::NAN;
+        let error = InputMesh::decode(&MeshDecl {
+            face_vertex_counts: &counts,
+            ..decl(&positions, &indices)
+        });
+        assert_eq!(error.err(), Some(AddMeshError::InvalidVertexData));
+    }
+
+    #[test]
+    fn face_lookup_matches_rotated_triangles() {
+        let (positions, indices) = tube(4);
+        let ignore = [false, true, false, false, false, false, false, false];
+        let mesh = InputMesh::decode(&MeshDecl {
+            face_ignore_data: &ignore,
+            ..decl(&positions, &indices)
+        })
+        .unwrap();
+        let part = &mesh.parts[0];
+        let mut faces = FaceLookup::new(&mesh, part);
+
+        let t = &part.indices[6..9];
+        assert_eq!(faces.take([t[1], t[2], t[0]]), Some(part.faces[2]));
+        assert_eq!(faces.take([t[1], t[2], t[0]]), None);
+        // Opposite winding is a different face
+        assert_eq!(faces.take([t[0], t[2], t[1]]), None);
+        let t = &part.indices[3..6];
+        assert_eq!(faces.take([t[0], t[1], t[2]]), None);
+    }
+}
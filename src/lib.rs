@@ -1,14 +1,30 @@
 #![allow(unused)]
 
+pub mod bake;
 mod bindings;
+#[cfg(test)]
+mod fixtures;
+mod input;
+#[cfg(feature = "lightmap")]
+pub mod lightmap;
+pub mod lod;
+mod math;
+pub mod pack;
+pub mod param;
+pub mod preprocess;
+mod projection;
+mod raster;
+mod tangent;
+pub mod topology;
 use bindings::root::xatlas;
+use input::{FaceLookup, InputMesh};
 
+use std::collections::HashMap;
 use std::ops::Drop;
+use std::sync::{Mutex, MutexGuard};
 
 use crate::bindings::*;
 
-// jb-todo: find a way to call ParameterizeFunc wrapped in a closure
-
 #[derive(Debug)]
 pub enum IndexFormat {
     Uint16,
@@ -32,7 +48,7 @@ pub enum ProgressCategory {
 }
 
 #[repr(C)]
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct MeshDecl<'a> {
     pub vertex_count: u32,
     pub vertex_position_data: &'a [u8],
@@ -46,8 +62,93 @@ pub struct MeshDecl<'a> {
     pub index_offset: i32,
     pub index_format: IndexFormat,
     pub face_ignore_data: &'a [bool],
+    // Optional number of vertices of each face, `index_data` then holds polygons instead of triangles.
+    // Polygons are triangulated internally and all per-face data refers to the polygons.
+    pub face_vertex_counts: &'a [u8],
+    // Scales the texel density of this mesh relative to `PackOptions::texels_per_unit`. Meshes are
+    // scaled by their importance before charting, so `ChartOptions::max_chart_area` and
+    // `max_boundary_length` apply to the scaled surface.
+    pub importance: f32,
+    // Optional per-face multiplier on top of `importance`, faces with different values never share a chart
+    pub face_importance_data: &'a [f32],
+    // Optional per-face group id (material, smoothing group), faces in different groups never share a chart
+    pub face_group_data: &'a [u32],
+    // Optional pairs of vertices that must lie on a chart boundary, as final vertex indices with
+    // `index_offset` already added. Seams that don't cut the surface apart add another chart
+    // boundary away from the seam.
+    pub seam_edge_data: &'a [[u32; 2]],
+    // Unwrap by projection instead of xatlas' charting, see ProjectionMode
+    pub projection: Option<ProjectionMode>,
+}
+
+impl<'a> Default for MeshDecl<'a> {
+    fn default() -> MeshDecl<'a> {
+        MeshDecl {
+            vertex_count: 0,
+            vertex_position_data: &[],
+            vertex_position_stride: 0,
+            vertex_normal_data: &[],
+            vertex_normal_stride: 0,
+            vertex_uv_data: &[],
+            vertex_uv_stride: 0,
+            index_count: 0,
+            index_data: &[],
+            index_offset: 0,
+            index_format: IndexFormat::default(),
+            face_ignore_data: &[],
+            face_vertex_counts: &[],
+            importance: 1.0,
+            face_importance_data: &[],
+            face_group_data: &[],
+            seam_edge_data: &[],
+            projection: None,
+        }
+    }
 }
 
+// Faces are charted by the projection they fall into and every connected set of faces is
+// flattened with it, the resulting charts are packed like any other.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProjectionMode {
+    Planar,      // along the dominant normal of the mesh, front and back faces apart
+    Box,         // along the major axis closest to each face normal
+    Cylindrical, // around the longest axis of the bounds, with planar caps
+    Spherical,   // latitude and longitude around the bounds center, with planar poles
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AddMeshError {
+    IndexOutOfRange,
+    InvalidIndexCount,
+    InvalidFaceVertexCount,
+    InvalidVertexData,
+    InvalidFaceData,
+    InvalidImportance,
+    InvalidSeamEdge,
+}
+
+impl std::fmt::Display for AddMeshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            AddMeshError::IndexOutOfRange => "index out of range",
+            AddMeshError::InvalidIndexCount => {
+                "index count is not a multiple of 3 or larger than the index data"
+            }
+            AddMeshError::InvalidFaceVertexCount => {
+                "face vertex counts are below 3 or don't add up to the index count"
+            }
+            AddMeshError::InvalidVertexData => {
+                "vertex data is too short for the given count and stride or has non-finite positions"
+            }
+            AddMeshError::InvalidFaceData => "per-face data does not match the face count",
+            AddMeshError::InvalidImportance => "importance must be positive and finite",
+            AddMeshError::InvalidSeamEdge => "seam edge is not an edge of any face",
+        })
+    }
+}
+
+impl std::error::Error for AddMeshError {}
+
 #[derive(Debug)]
 pub struct ChartOptions {
     pub proxy_fit_metric_weight: f32,
@@ -55,8 +156,8 @@ pub struct ChartOptions {
     pub straightness_metric_weight: f32,
     pub normal_seam_metric_weight: f32,
     pub texture_seam_metric_weight: f32,
-    pub max_chart_area: f32,
-    pub max_boundary_length: f32,
+    pub max_chart_area: f32, // in the units of the input scaled by `MeshDecl::importance`
+    pub max_boundary_length: f32, // in the units of the input scaled by `MeshDecl::importance`
     pub max_threshold: f32,
     pub grow_face_count: u32,
     pub max_iterations: u32,
@@ -96,6 +197,42 @@ impl Default for ChartOptions {
     }
 }
 
+#[derive(Debug, Default, Copy, Clone)]
+pub enum Parameterizer {
+    #[default]
+    Xatlas, // the built-in parameterization of xatlas
+    Lscm, // `param::lscm`, planar charts get projected
+    // `param::lscm` refined by `param::arap`, `Xatlas::refine_charts` refines any parameterization
+    Arap(param::arap::ArapOptions),
+}
+
+// ParameterizeFunc doesn't take user data, so the active Rust parameterizer is passed along in here.
+// Generating with a Rust parameterizer holds GENERATE_LOCK to keep concurrent atlases from
+// overwriting each other's choice, the built-in one needs neither.
+static ACTIVE_PARAMETERIZER: Mutex<Parameterizer> = Mutex::new(Parameterizer::Xatlas);
+static GENERATE_LOCK: Mutex<()> = Mutex::new(());
+
+impl Parameterizer {
+    // Makes this the parameterizer of the callback until the returned guard is dropped
+    fn activate(&self) -> Option<MutexGuard<'static, ()>> {
+        if let Parameterizer::Xatlas = self {
+            return None;
+        }
+        let guard = GENERATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        *ACTIVE_PARAMETERIZER
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = *self;
+        Some(guard)
+    }
+
+    fn convert(&self) -> xatlas::ParameterizeFunc {
+        match self {
+            Parameterizer::Xatlas => None,
+            _ => Some(parameterize_cb),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PackOptions {
     pub attempts: i32,
@@ -104,19 +241,158 @@ pub struct PackOptions {
     pub max_chart_size: u32,
     pub block_align: bool,
     pub conservative: bool,
-    pub padding: u32,
+    pub padding: u32, // in texels of `padding_mip_level`
+    pub strategy: pack::PackStrategy,
+    pub rotation: pack::PackRotation, // only used by the Rust strategies
+    pub max_pages: u32, // limit on the atlas count with a `resolution`, 0 is unlimited
+    pub overflow: PageOverflow,
+    // 0 is unlimited, overrides `resolution` as the page width and height. Pages that are not
+    // square are packed with the skyline packer even with `PackStrategy::Xatlas`.
+    pub max_width: u32,
+    pub max_height: u32,
+    pub power_of_two: bool,
+    // Preferred width over height when the width is unlimited, 0 is square. xatlas only packs
+    // square pages, other ratios and page limits use the skyline packer instead.
+    pub aspect_ratio: f32,
+    // Block size used by `block_align`. xatlas only aligns to 4x4 blocks, other sizes are packed
+    // with the skyline packer even with `PackStrategy::Xatlas`.
+    pub block_size: [u32; 2],
+    // Mip level the padding has to hold up to. Charts are also placed on the texel grid of that
+    // level so no texel of it is shared between charts, which xatlas can't do, so above level 0
+    // `PackStrategy::Xatlas` uses the skyline packer as well. Levels above 16 are treated as 16.
+    pub padding_mip_level: u32,
 }
 
+// What happens when the charts need more than `PackOptions::max_pages` pages
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PageOverflow {
+    #[default]
+    SpillToNewPage, // add as many pages as needed, the limit is not enforced
+    ShrinkDensity, // lower the texel density until the charts fit
+    Error,         // fail with `PackError::TooManyPages`
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PackError {
+    TooManyPages { pages: u32, max_pages: u32 },
+}
+
+impl std::fmt::Display for PackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PackError::TooManyPages { pages, max_pages } => write!(
+                f,
+                "charts need {} pages but at most {} are allowed",
+                pages, max_pages
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
 impl PackOptions {
+    // The strategy that actually packs, `PackStrategy::Xatlas` can only fill square pages on a
+    // grid of 4x4 blocks so it falls back to the skyline packer for anything else
+    pub(crate) fn packer(&self) -> pack::PackStrategy {
+        let [width, height] = self.size_limits();
+        let square_pages = width == 0 || height == 0 || width == height;
+        let square_preference = width > 0 || self.aspect_ratio <= 0.0 || self.aspect_ratio == 1.0;
+        let xatlas_grid =
+            (!self.block_align || self.block_size == [4, 4]) && self.padding_mip_level == 0;
+        match self.strategy {
+            pack::PackStrategy::Xatlas if !square_pages || !square_preference || !xatlas_grid => {
+                pack::PackStrategy::Skyline
+            }
+            strategy => strategy,
+        }
+    }
+
+    // Largest page width and height, 0 where unlimited
+    pub(crate) fn size_limits(&self) -> [u32; 2] {
+        let limit = |max: u32| {
+            let limit = if max > 0 { max } else { self.resolution };
+            if self.power_of_two && limit > 0 {
+                1 << (31 - limit.leading_zeros())
+            } else {
+                limit
+            }
+        };
+        [limit(self.max_width), limit(self.max_height)]
+    }
+
+    // Atlas size for a packing that uses `used` texels, pages sized by `resolution` are never trimmed
+    pub(crate) fn atlas_size(&self, used: [u32; 2]) -> [u32; 2] {
+        let limits = self.size_limits();
+        let size = |used: u32, max: u32, limit: u32| {
+            if max == 0 && self.resolution > 0 {
+                limit
+            } else if self.power_of_two {
+                used.max(1).next_power_of_two()
+            } else {
+                used
+            }
+        };
+        let [x, y] = self.alignment();
+        [
+            size(used[0].div_ceil(x) * x, self.max_width, limits[0]),
+            size(used[1].div_ceil(y) * y, self.max_height, limits[1]),
+        ]
+    }
+
+    fn mip_level(&self) -> u32 {
+        self.padding_mip_level.min(16)
+    }
+
+    pub(crate) fn texel_padding(&self) -> u32 {
+        self.padding.saturating_mul(1 << self.mip_level())
+    }
+
+    // Grid chart sizes and positions are snapped to, blocks and the texels of the padding mip level
+    pub(crate) fn alignment(&self) -> [u32; 2] {
+        fn lcm(a: u32, b: u32) -> u32 {
+            let (mut x, mut y) = (a, b);
+            while y != 0 {
+                (x, y) = (y, x % y);
+            }
+            a / x * b
+        }
+
+        let mip = 1 << self.mip_level();
+        let block = if self.block_align {
+            [self.block_size[0].max(1), self.block_size[1].max(1)]
+        } else {
+            [1, 1]
+        };
+        let align = [lcm(block[0], mip), lcm(block[1], mip)];
+        // Rotated charts swap their sides, so those need the same alignment on both axes
+        if self.rotation != pack::PackRotation::None {
+            let both = lcm(align[0], align[1]);
+            [both, both]
+        } else {
+            align
+        }
+    }
+
+    // The Rust strategies repack the output afterwards, xatlas only needs a single attempt then
     fn convert(&self) -> xatlas::PackOptions {
         xatlas::PackOptions {
-            attempts: self.attempts,
+            attempts: match self.packer() {
+                pack::PackStrategy::Xatlas => self.attempts,
+                _ => 1,
+            },
             texelsPerUnit: self.texels_per_unit,
-            resolution: self.resolution,
+            // xatlas pages are square, use the smaller side
+            resolution: if self.resolution > 0 {
+                let [width, height] = self.size_limits();
+                width.min(height)
+            } else {
+                0
+            },
             maxChartSize: self.max_chart_size,
             blockAlign: self.block_align,
             conservative: self.conservative,
-            padding: self.padding,
+            padding: self.texel_padding(),
         }
     }
 }
@@ -131,6 +407,16 @@ impl Default for PackOptions {
             block_align: false,
             conservative: false,
             padding: 0,
+            strategy: pack::PackStrategy::default(),
+            rotation: pack::PackRotation::default(),
+            max_pages: 0,
+            overflow: PageOverflow::default(),
+            max_width: 0,
+            max_height: 0,
+            power_of_two: false,
+            aspect_ratio: 0.0,
+            block_size: [4, 4],
+            padding_mip_level: 0,
         }
     }
 }
@@ -139,10 +425,11 @@ impl Default for PackOptions {
 pub struct Chart<'a> {
     pub atlas_index: u32,
     pub indices: &'a [u32],
+    pub texels_per_unit: f32, // effective density after applying importance
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct Vertex {
     pub atlas_index: u32,
     pub uv: [f32; 2],
@@ -151,14 +438,259 @@ pub struct Vertex {
 
 #[derive(Debug)]
 pub struct Mesh<'a> {
-    pub charts: Vec<Chart<'a>>, // need to translate Chart so it's owned
+    pub charts: Vec<Chart<'a>>,
     pub indices: &'a [u32],
     pub vertices: &'a [Vertex],
+    pub texels_per_unit: f32, // effective density after applying `MeshDecl::importance`
+    // Input face (polygon when using `face_vertex_counts`) of every output triangle, u32::MAX for
+    // triangles whose vertices match no input face, which xatlas doesn't produce for valid input
+    pub original_face: &'a [u32],
+}
+
+// Tangent frames of the output vertices of a mesh, see `Mesh::compute_tangents`
+#[derive(Debug, Default, Clone)]
+pub struct Tangents {
+    pub indices: Vec<u32>, // `Mesh::indices`, with the corners of split vertices moved to the copies
+    pub remap: Vec<u32>,   // vertex of `Mesh::vertices` for every vertex, the first ones are those
+    pub tangents: Vec<[f32; 4]>, // one per vertex of `remap`
+}
+
+impl<'a> Mesh<'a> {
+    // MikkTSpace tangents for the atlas uvs, one per output vertex. A vertex shared by faces with
+    // mirrored and regular uvs needs two tangents, so it is copied to the end for one side and
+    // `Tangents::indices` and `Tangents::remap` replace `indices` and the vertex order.
+    // `positions` and `normals` are the input vertex data, looked up through `Vertex::xref`;
+    // without normals area weighted normals of the output triangles are used. The w component is
+    // the sign of the bitangent, `bitangent = w * cross(normal, tangent)`.
+    pub fn compute_tangents(&self, positions: &[[f32; 3]], normals: &[[f32; 3]]) -> Tangents {
+        if normals.is_empty() {
+            let indices: Vec<u32> = self
+                .indices
+                .iter()
+                .map(|&i| self.vertices[i as usize].xref)
+                .collect();
+            let normals = tangent::vertex_normals(positions, &indices);
+            return tangent::compute_tangents(self.indices, self.vertices, positions, &normals);
+        }
+        tangent::compute_tangents(self.indices, self.vertices, positions, normals)
+    }
+}
+
+// Two charts that end up closer than the required gutter in some mip level
+#[derive(Debug, Copy, Clone)]
+pub struct GutterViolation {
+    pub atlas_index: u32,
+    pub charts: [(u32, u32); 2], // mesh and chart index
+    pub texel: [u32; 2],         // in the mip level that was validated
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ChartRepair {
+    pub mesh_index: u32,
+    pub chart_index: u32,
+    pub flipped_faces: u32,     // before the repair
+    pub overlapping_faces: u32, // before the repair
+    pub texels_per_unit: f32,   // of the repaired chart
+}
+
+// Owned copies of the xatlas output, with the parts of every input mesh merged back together
+#[derive(Debug, Default)]
+struct ChartOutput {
+    atlas_index: u32,
+    indices: Vec<u32>,
+    texels_per_unit: f32,
+}
+
+#[derive(Debug, Default)]
+struct MeshOutput {
+    charts: Vec<ChartOutput>,
+    indices: Vec<u32>,
+    vertices: Vec<Vertex>,
+    texels_per_unit: f32,
+    original_face: Vec<u32>,
+}
+
+impl MeshOutput {
+    // Triangles of a chart on its own vertices, welded by position and uv so output vertices that
+    // xatlas split inside the chart (normal seams and such) stay connected, while the two sides of
+    // a cut through the chart stay apart. Returns the output vertex of every chart vertex and the
+    // triangles.
+    fn chart_mesh(&self, input: &InputMesh, chart: usize) -> (Vec<u32>, Vec<u32>) {
+        let mut lookup = HashMap::new();
+        let mut local_vertices = vec![];
+        let local_indices = self.charts[chart]
+            .indices
+            .iter()
+            .map(|&i| {
+                let vertex = &self.vertices[i as usize];
+                let p = input.positions[vertex.xref as usize];
+                let key = [
+                    p[0].to_bits(),
+                    p[1].to_bits(),
+                    p[2].to_bits(),
+                    vertex.uv[0].to_bits(),
+                    vertex.uv[1].to_bits(),
+                ];
+                *lookup.entry(key).or_insert_with(|| {
+                    local_vertices.push(i);
+                    local_vertices.len() as u32 - 1
+                })
+            })
+            .collect();
+        (local_vertices, local_indices)
+    }
+
+    fn write_chart(&mut self, chart: usize, local_indices: &[u32], uvs: &[[f32; 2]]) {
+        for (&i, &local) in self.charts[chart].indices.iter().zip(local_indices) {
+            self.vertices[i as usize].uv = uvs[local as usize];
+        }
+    }
+
+    // uv triangles of a chart
+    fn chart_triangles(&self, chart: usize) -> Vec<[[f32; 2]; 3]> {
+        let uv = |i: u32| self.vertices[i as usize].uv;
+        self.charts[chart]
+            .indices
+            .chunks_exact(3)
+            .map(|t| [uv(t[0]), uv(t[1]), uv(t[2])])
+            .collect()
+    }
+}
+
+// Shrinks new uvs of a chart where needed and centers them within the bounds of `old_uvs`, so
+// the chart keeps its place in the atlas. Returns the scale applied.
+fn fit_chart(old_uvs: &[[f32; 2]], uvs: &mut [[f32; 2]]) -> f32 {
+    let bounds = |uvs: &[[f32; 2]]| {
+        uvs.iter().fold(
+            ([f32::MAX, f32::MAX], [f32::MIN, f32::MIN]),
+            |(min, max), uv| {
+                (
+                    [min[0].min(uv[0]), min[1].min(uv[1])],
+                    [max[0].max(uv[0]), max[1].max(uv[1])],
+                )
+            },
+        )
+    };
+    let (old_min, old_max) = bounds(old_uvs);
+    let (new_min, new_max) = bounds(uvs);
+    let fit = ((old_max[0] - old_min[0]) / (new_max[0] - new_min[0]))
+        .min((old_max[1] - old_min[1]) / (new_max[1] - new_min[1]))
+        .min(1.0);
+    for uv in uvs.iter_mut() {
+        *uv = [
+            (old_min[0] + old_max[0]) * 0.5 + (uv[0] - (new_min[0] + new_max[0]) * 0.5) * fit,
+            (old_min[1] + old_max[1]) * 0.5 + (uv[1] - (new_min[1] + new_max[1]) * 0.5) * fit,
+        ];
+    }
+    fit
+}
+
+// Local triangles of a chart with the given uvs
+fn local_triangles(local_indices: &[u32], uvs: &[[f32; 2]]) -> Vec<[[f32; 2]; 3]> {
+    local_indices
+        .chunks_exact(3)
+        .map(|t| [uvs[t[0] as usize], uvs[t[1] as usize], uvs[t[2] as usize]])
+        .collect()
+}
+
+// The chart that covers every texel of every page, charts numbered across all meshes in order
+// and u32::MAX where there is none. Texels are those touched by the (scaled) chart triangles.
+struct ChartOwners {
+    size: [u32; 2],
+    scale: f32,
+    pages: Vec<Vec<u32>>,
+}
+
+impl ChartOwners {
+    // Gaps between charts wider than this aren't measured
+    const MAX_CLEARANCE: u32 = 8;
+
+    // Texels the triangles touch, as indices into a page
+    fn texels(&self, triangles: &[[[f32; 2]; 3]]) -> Vec<u32> {
+        let mut texels = vec![];
+        let scaled = |uv: [f32; 2]| [uv[0] * self.scale, uv[1] * self.scale];
+        for t in triangles {
+            let t = [scaled(t[0]), scaled(t[1]), scaled(t[2])];
+            raster::rasterize_triangle(t, self.size, true, |x, y| {
+                texels.push(y * self.size[0] + x)
+            });
+        }
+        texels.sort_unstable();
+        texels.dedup();
+        texels
+    }
+
+    // Distance in texels (along both axes) from `texels` to the closest one of a chart other than
+    // `id`, up to `MAX_CLEARANCE`. 0 where they overlap.
+    fn clearance(&self, page: u32, id: u32, texels: &[u32]) -> u32 {
+        let owners = &self.pages[page as usize];
+        let (width, height) = (self.size[0] as i64, self.size[1] as i64);
+        let mut clearance = Self::MAX_CLEARANCE;
+        for &texel in texels {
+            let (x, y) = ((texel % self.size[0]) as i64, (texel / self.size[0]) as i64);
+            let reach = clearance as i64;
+            for ny in (y - reach).max(0)..=(y + reach).min(height - 1) {
+                for nx in (x - reach).max(0)..=(x + reach).min(width - 1) {
+                    let other = owners[(ny * width + nx) as usize];
+                    if other != u32::MAX && other != id {
+                        let distance = (nx - x).abs().max((ny - y).abs()) as u32;
+                        clearance = clearance.min(distance);
+                    }
+                }
+            }
+        }
+        clearance
+    }
+
+    // Moves chart `id` to new triangles if that doesn't bring it closer to any other chart than
+    // it was before
+    fn try_move(
+        &mut self,
+        page: u32,
+        id: u32,
+        old: &[[[f32; 2]; 3]],
+        new: &[[[f32; 2]; 3]],
+    ) -> bool {
+        if page as usize >= self.pages.len() {
+            return true;
+        }
+        let (old, new) = (self.texels(old), self.texels(new));
+        if self.clearance(page, id, &new) < self.clearance(page, id, &old) {
+            return false;
+        }
+        let owners = &mut self.pages[page as usize];
+        for owner in owners.iter_mut().filter(|owner| **owner == id) {
+            *owner = u32::MAX;
+        }
+        for &texel in &new {
+            if owners[texel as usize] == u32::MAX {
+                owners[texel as usize] = id;
+            }
+        }
+        true
+    }
+}
+
+// Sum of the signs of the uv areas, negative for charts that are mostly clockwise
+fn chart_orientation(indices: &[u32], uvs: &[[f32; 2]]) -> f32 {
+    indices
+        .chunks_exact(3)
+        .map(|t| {
+            math::signed_area2(uvs[t[0] as usize], uvs[t[1] as usize], uvs[t[2] as usize]).signum()
+        })
+        .sum()
 }
 
 #[derive(Debug)]
 pub struct Xatlas {
     handle: *mut root::xatlas::Atlas,
+    inputs: Vec<InputMesh>,
+    outputs: Vec<MeshOutput>,
+    width: u32,
+    height: u32,
+    atlas_count: u32,
+    utilization: Vec<f32>,
+    texels_per_unit: f32,
 }
 
 unsafe extern "C" fn progress_cb(
@@ -180,145 +712,1109 @@ unsafe extern "C" fn progress_cb(
     );
 }
 
-impl<'a> Xatlas {
+unsafe extern "C" fn parameterize_cb(
+    positions: *const f32,
+    texcoords: *mut f32,
+    vertex_count: u32,
+    indices: *const u32,
+    index_count: u32,
+    is_planar: bool,
+) {
+    let positions = std::slice::from_raw_parts(positions as *const [f32; 3], vertex_count as usize);
+    let texcoords =
+        std::slice::from_raw_parts_mut(texcoords as *mut [f32; 2], vertex_count as usize);
+    let indices = std::slice::from_raw_parts(indices, index_count as usize);
+
+    // Unwinding into xatlas would abort, a chart that panics is left collapsed to a point
+    let parameterized = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        if is_planar {
+            param::planar_projection(positions, indices, texcoords);
+            return;
+        }
+
+        let parameterizer = *ACTIVE_PARAMETERIZER
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        param::lscm::parameterize(positions, indices, texcoords);
+        if let Parameterizer::Arap(options) = parameterizer {
+            param::arap::refine(positions, indices, texcoords, &options);
+        }
+    }));
+    if parameterized.is_err() {
+        texcoords.iter_mut().for_each(|uv| *uv = [0.0; 2]);
+    }
+}
+
+impl Xatlas {
     pub fn new() -> Self {
         Self {
             handle: unsafe { xatlas::Create() },
+            inputs: vec![],
+            outputs: vec![],
+            width: 0,
+            height: 0,
+            atlas_count: 0,
+            utilization: vec![],
+            texels_per_unit: 0.0,
         }
     }
 
-    pub fn add_mesh(&self, decl_param: &MeshDecl) {
-        let decl = xatlas::MeshDecl {
-            vertexCount: decl_param.vertex_count,
-            vertexPositionData: decl_param.vertex_position_data.as_ptr() as _,
-            vertexPositionStride: decl_param.vertex_position_stride,
-            vertexNormalData: if decl_param.vertex_normal_data.is_empty() {
-                std::ptr::null()
-            } else {
-                decl_param.vertex_normal_data.as_ptr() as _
-            },
-            vertexNormalStride: decl_param.vertex_normal_stride,
-            vertexUvData: if decl_param.vertex_uv_data.is_empty() {
-                std::ptr::null()
-            } else {
-                decl_param.vertex_uv_data.as_ptr() as _
-            },
-            vertexUvStride: decl_param.vertex_uv_stride,
-            indexCount: decl_param.index_count,
-            indexData: if decl_param.index_data.is_empty() {
-                std::ptr::null()
-            } else {
-                decl_param.index_data.as_ptr() as _
-            },
-            indexOffset: decl_param.index_offset,
-            indexFormat: match decl_param.index_format {
-                IndexFormat::Uint16 => xatlas::IndexFormat_Enum_UInt16,
-                IndexFormat::Uint32 => xatlas::IndexFormat_Enum_UInt32,
-            },
-            faceIgnoreData: if decl_param.face_ignore_data.is_empty() {
-                std::ptr::null()
-            } else {
-                decl_param.face_ignore_data.as_ptr() as _
-            },
-        };
+    pub fn add_mesh(&mut self, decl_param: &MeshDecl) -> Result<(), AddMeshError> {
+        let input = InputMesh::decode(decl_param)?;
 
-        unsafe {
-            xatlas::AddMesh(self.handle, &decl);
+        // xatlas can't remove meshes again, so every part is checked before the first is added
+        for part in &input.parts {
+            if part.indices.len() % 3 != 0 {
+                return Err(AddMeshError::InvalidIndexCount);
+            }
+            if part
+                .indices
+                .iter()
+                .any(|&i| i as usize >= part.vertices.len())
+            {
+                return Err(AddMeshError::IndexOutOfRange);
+            }
         }
+
+        for part in &input.parts {
+            let positions = input.part_positions(part);
+            let normals = input.part_normals(part);
+            let uvs = input.part_uvs(part);
+            let face_ignore = input.part_face_ignore(part);
+
+            let decl = xatlas::MeshDecl {
+                vertexCount: positions.len() as u32,
+                vertexPositionData: positions.as_ptr() as _,
+                vertexPositionStride: std::mem::size_of::<[f32; 3]>() as u32,
+                vertexNormalData: if normals.is_empty() {
+                    std::ptr::null()
+                } else {
+                    normals.as_ptr() as _
+                },
+                vertexNormalStride: std::mem::size_of::<[f32; 3]>() as u32,
+                vertexUvData: if uvs.is_empty() {
+                    std::ptr::null()
+                } else {
+                    uvs.as_ptr() as _
+                },
+                vertexUvStride: std::mem::size_of::<[f32; 2]>() as u32,
+                indexCount: part.indices.len() as u32,
+                indexData: part.indices.as_ptr() as _,
+                indexOffset: 0,
+                indexFormat: xatlas::IndexFormat_Enum_UInt32,
+                faceIgnoreData: if face_ignore.is_empty() {
+                    std::ptr::null()
+                } else {
+                    face_ignore.as_ptr()
+                },
+            };
+
+            // Only fails on the conditions checked above
+            let error = unsafe { xatlas::AddMesh(self.handle, &decl) };
+            debug_assert_eq!(error, xatlas::AddMeshError_Enum_Success);
+        }
+
+        self.inputs.push(input);
+        Ok(())
     }
 
-    pub fn generate_simple(&self, chart_opts: ChartOptions, pack_opts: PackOptions) {
+    pub fn generate_simple(
+        &mut self,
+        chart_opts: ChartOptions,
+        parameterizer: Parameterizer,
+        pack_opts: PackOptions,
+    ) -> Result<(), PackError> {
         let chart_opts = chart_opts.convert();
-        let pack_opts = pack_opts.convert();
 
+        let _active = parameterizer.activate();
         unsafe {
             xatlas::Generate(
                 self.handle,
                 chart_opts,
-                None,
-                pack_opts,
+                parameterizer.convert(),
+                pack_opts.convert(),
                 None,
                 std::ptr::null_mut(),
             )
         }
+
+        self.build_outputs();
+        self.finish_packing(&pack_opts)
     }
 
-    pub fn generate<F>(&self, chart_opts: ChartOptions, pack_opts: PackOptions, mut progress: F)
+    pub fn generate<F>(
+        &mut self,
+        chart_opts: ChartOptions,
+        parameterizer: Parameterizer,
+        pack_opts: PackOptions,
+        mut progress: F,
+    ) -> Result<(), PackError>
     where
         F: FnMut(ProgressCategory, i32),
     {
         let chart_opts = chart_opts.convert();
-        let pack_opts = pack_opts.convert();
 
         let mut cb: &mut FnMut(ProgressCategory, i32) = &mut progress;
         let cb = &mut cb as *mut &mut FnMut(ProgressCategory, i32) as *mut std::ffi::c_void;
 
+        let _active = parameterizer.activate();
         unsafe {
             xatlas::Generate(
                 self.handle,
                 chart_opts,
-                None,
-                pack_opts,
+                parameterizer.convert(),
+                pack_opts.convert(),
                 Some(progress_cb),
                 cb,
             )
         }
+
+        self.build_outputs();
+        self.finish_packing(&pack_opts)
+    }
+
+    pub fn texels_per_unit(&self) -> f32 {
+        self.texels_per_unit
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
     }
 
-    pub fn meshes(&mut self) -> Vec<Mesh<'a>> {
-        // shallow copy most data
-        let mut meshes = vec![];
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn atlas_count(&self) -> u32 {
+        self.atlas_count
+    }
+
+    // Fraction of the texels of every page that is covered by charts
+    pub fn utilization(&self) -> &[f32] {
+        &self.utilization
+    }
 
+    // Checks that in `mip_level` of the atlas textures every texel touched by a chart is at least
+    // `gutter` texels away from those touched by any other chart, which keeps charts from
+    // bleeding into each other when sampled at that level. Reports every pair of charts once.
+    pub fn validate_gutters(&self, mip_level: u32, gutter: u32) -> Vec<GutterViolation> {
+        let mut charts = vec![];
+        for (mesh_index, output) in self.outputs.iter().enumerate() {
+            for chart_index in 0..output.charts.len() {
+                charts.push((mesh_index as u32, chart_index as u32));
+            }
+        }
+
+        let mut violations = vec![];
+        let mut reported = std::collections::HashSet::new();
+        let mut report = |atlas_index: u32, a: u32, b: u32, x: u32, y: u32| {
+            if reported.insert((a.min(b), a.max(b))) {
+                violations.push(GutterViolation {
+                    atlas_index,
+                    charts: [charts[a as usize], charts[b as usize]],
+                    texel: [x, y],
+                });
+            }
+        };
+
+        let owners = self.chart_owners(mip_level, &mut report);
+        let size = owners.size;
+        let gutter = gutter as i64;
+        for (atlas_index, owner) in owners.pages.iter().enumerate() {
+            for y in 0..size[1] as i64 {
+                for x in 0..size[0] as i64 {
+                    let id = owner[(y * size[0] as i64 + x) as usize];
+                    if id == u32::MAX {
+                        continue;
+                    }
+                    for ny in (y - gutter).max(0)..=(y + gutter).min(size[1] as i64 - 1) {
+                        for nx in (x - gutter).max(0)..=(x + gutter).min(size[0] as i64 - 1) {
+                            let other = owner[(ny * size[0] as i64 + nx) as usize];
+                            if other != u32::MAX && other != id {
+                                report(atlas_index as u32, id, other, x as u32, y as u32);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        violations
+    }
+
+    // Texels of every page covered by the charts in `mip_level`, `overlap` is called with the page,
+    // both charts and the texel where two charts touch the same texel
+    fn chart_owners<F>(&self, mip_level: u32, mut overlap: F) -> ChartOwners
+    where
+        F: FnMut(u32, u32, u32, u32, u32),
+    {
+        let size = [
+            self.width.checked_shr(mip_level).unwrap_or(0).max(1),
+            self.height.checked_shr(mip_level).unwrap_or(0).max(1),
+        ];
+        let mut owners = ChartOwners {
+            size,
+            scale: 0.5f32.powf(mip_level as f32),
+            pages: vec![vec![u32::MAX; (size[0] * size[1]) as usize]; self.atlas_count as usize],
+        };
+
+        let mut id = 0;
+        for output in &self.outputs {
+            for (chart_index, chart) in output.charts.iter().enumerate() {
+                let texels = owners.texels(&output.chart_triangles(chart_index));
+                if let Some(page) = owners.pages.get_mut(chart.atlas_index as usize) {
+                    for texel in texels {
+                        let owner = &mut page[texel as usize];
+                        if *owner == u32::MAX {
+                            *owner = id;
+                        } else if *owner != id {
+                            let (x, y) = (texel % owners.size[0], texel / owners.size[0]);
+                            overlap(chart.atlas_index, *owner, id, x, y);
+                        }
+                    }
+                }
+                id += 1;
+            }
+        }
+        owners
+    }
+
+    // Packs the output charts again, with xatlas this rebuilds the output and discards any
+    // changes made to it (such as `repair_charts`), the Rust strategies pack the current output.
+    pub fn repack(&mut self, pack_opts: &PackOptions) -> Result<(), PackError> {
+        if pack_opts.packer() == pack::PackStrategy::Xatlas {
+            self.pack_xatlas(pack_opts.convert());
+        }
+        self.finish_packing(pack_opts)
+    }
+
+    fn pack_xatlas(&mut self, pack_opts: xatlas::PackOptions) {
+        unsafe { xatlas::PackCharts(self.handle, pack_opts, None, std::ptr::null_mut()) }
+        self.build_outputs();
+    }
+
+    // Runs the Rust packer if requested and enforces the page limit on the packed output
+    fn finish_packing(&mut self, pack_opts: &PackOptions) -> Result<(), PackError> {
+        let packer = pack_opts.packer();
+        if packer != pack::PackStrategy::Xatlas {
+            self.pack_outputs(pack_opts);
+        }
+
+        // Exceeding the size limits (xatlas doesn't know about them, and a chart can be larger
+        // than a page) always lowers the density, too many pages only if the policy says so.
+        // Pages sized by `resolution` are already clamped, so the charts are measured instead.
+        let max_pages = pack_opts.max_pages;
+        let limits = pack_opts.size_limits();
+        let shrink_pages = pack_opts.overflow == PageOverflow::ShrinkDensity;
+        for _ in 0..32 {
+            let mut scale = 1.0f32;
+            let used = self.uv_extent();
+            let sizes = [self.width.max(used[0]), self.height.max(used[1])];
+            for (&size, &limit) in sizes.iter().zip(&limits) {
+                if limit > 0 && size > limit {
+                    scale = scale.min(limit as f32 / size as f32);
+                }
+            }
+            // The page count goes with the chart area, so with the square of the density
+            if shrink_pages && max_pages > 0 && self.atlas_count > max_pages {
+                scale = scale.min((max_pages as f32 / self.atlas_count as f32).sqrt());
+            }
+            if scale >= 1.0 {
+                break;
+            }
+
+            let scale = scale.min(0.95);
+            if packer == pack::PackStrategy::Xatlas {
+                let mut options = pack_opts.convert();
+                options.texelsPerUnit = self.texels_per_unit * scale;
+                self.pack_xatlas(options);
+            } else {
+                self.scale_outputs(scale);
+                self.pack_outputs(pack_opts);
+            }
+        }
+
+        if packer == pack::PackStrategy::Xatlas {
+            let [width, height] = pack_opts.atlas_size([self.width, self.height]);
+            let area = |w: u32, h: u32| (w as f32 * h as f32).max(1.0);
+            for utilization in &mut self.utilization {
+                *utilization *= area(self.width, self.height) / area(width, height);
+            }
+            self.width = width;
+            self.height = height;
+        }
+
+        if max_pages > 0
+            && self.atlas_count > max_pages
+            && pack_opts.overflow != PageOverflow::SpillToNewPage
+        {
+            return Err(PackError::TooManyPages {
+                pages: self.atlas_count,
+                max_pages,
+            });
+        }
+        Ok(())
+    }
+
+    // Texels spanned by the output uvs, on any page
+    fn uv_extent(&self) -> [u32; 2] {
+        let mut extent = [0.0f32; 2];
+        for v in self.outputs.iter().flat_map(|output| &output.vertices) {
+            extent = [extent[0].max(v.uv[0]), extent[1].max(v.uv[1])];
+        }
+        [extent[0].ceil() as u32, extent[1].ceil() as u32]
+    }
+
+    fn scale_outputs(&mut self, scale: f32) {
+        for output in &mut self.outputs {
+            for v in &mut output.vertices {
+                v.uv = [v.uv[0] * scale, v.uv[1] * scale];
+            }
+            for chart in &mut output.charts {
+                chart.texels_per_unit *= scale;
+            }
+            output.texels_per_unit *= scale;
+        }
+        self.texels_per_unit *= scale;
+    }
+
+    fn pack_outputs(&mut self, pack_opts: &PackOptions) {
+        // Output vertices aren't shared between charts, so every chart moves its own vertices
+        let mut chart_vertices = vec![];
+        let mut charts = vec![];
+        for (mesh_index, output) in self.outputs.iter().enumerate() {
+            for chart in &output.charts {
+                let mut vertices = chart.indices.clone();
+                vertices.sort_unstable();
+                vertices.dedup();
+                charts.push(
+                    vertices
+                        .iter()
+                        .map(|&v| output.vertices[v as usize].uv)
+                        .collect(),
+                );
+                chart_vertices.push((mesh_index, vertices));
+            }
+        }
+
+        let packing = pack::pack_charts(&mut charts, pack_opts);
+
+        let mut placements = packing.placements.iter();
+        for ((mesh_index, vertices), uvs) in chart_vertices.iter().zip(&charts) {
+            let output = &mut self.outputs[*mesh_index];
+            let atlas_index = placements.next().unwrap().atlas_index;
+            for (&v, &uv) in vertices.iter().zip(uvs) {
+                output.vertices[v as usize].uv = uv;
+                output.vertices[v as usize].atlas_index = atlas_index;
+            }
+        }
+
+        let mut placements = packing.placements.iter();
+        for output in &mut self.outputs {
+            for chart in &mut output.charts {
+                chart.atlas_index = placements.next().unwrap().atlas_index;
+            }
+        }
+
+        self.width = packing.width;
+        self.height = packing.height;
+        self.atlas_count = packing.atlas_count;
+
+        self.utilization = vec![0.0; packing.atlas_count as usize];
+        for output in &self.outputs {
+            for chart in &output.charts {
+                let uv = |i: u32| output.vertices[i as usize].uv;
+                let area: f32 = chart
+                    .indices
+                    .chunks_exact(3)
+                    .map(|t| math::signed_area2(uv(t[0]), uv(t[1]), uv(t[2])).abs() * 0.5)
+                    .sum();
+                self.utilization[chart.atlas_index as usize] += area;
+            }
+        }
+        let page_area = (packing.width as f32 * packing.height as f32).max(1.0);
+        for utilization in &mut self.utilization {
+            *utilization /= page_area;
+        }
+    }
+
+    pub fn meshes(&self) -> Vec<Mesh<'_>> {
+        self.outputs
+            .iter()
+            .map(|output| Mesh {
+                charts: output
+                    .charts
+                    .iter()
+                    .map(|chart| Chart {
+                        atlas_index: chart.atlas_index,
+                        indices: &chart.indices,
+                        texels_per_unit: chart.texels_per_unit,
+                    })
+                    .collect(),
+                indices: &output.indices,
+                vertices: &output.vertices,
+                texels_per_unit: output.texels_per_unit,
+                original_face: &output.original_face,
+            })
+            .collect()
+    }
+
+    // Checks the output charts for flipped or self overlapping faces and re-parameterizes those with
+    // `param::tutte`. A repaired chart is scaled to its texel density, but shrunk to fit within its
+    // previous bounds so it keeps its place in the atlas, which lowers `Chart::texels_per_unit`.
+    // Charts that the embedding doesn't fix, or that would end up closer to another chart than
+    // they were, are left as they were and not reported.
+    pub fn repair_charts(&mut self) -> Vec<ChartRepair> {
+        let mut repairs = vec![];
+        let mut owners = self.chart_owners(0, |_, _, _, _, _| {});
+        let mut next_id = 0;
+
+        for (mesh_index, (output, input)) in self.outputs.iter_mut().zip(&self.inputs).enumerate() {
+            for chart_index in 0..output.charts.len() {
+                let id = next_id;
+                next_id += 1;
+                let (local_vertices, local_indices) = output.chart_mesh(input, chart_index);
+                let positions: Vec<[f32; 3]> = local_vertices
+                    .iter()
+                    .map(|&i| input.positions[output.vertices[i as usize].xref as usize])
+                    .collect();
+                let old_uvs: Vec<[f32; 2]> = local_vertices
+                    .iter()
+                    .map(|&i| output.vertices[i as usize].uv)
+                    .collect();
+
+                let flipped = param::flipped_faces(&local_indices, &old_uvs);
+                let overlapping = param::overlapping_faces(&local_indices, &old_uvs);
+                if flipped.is_empty() && overlapping.is_empty() {
+                    continue;
+                }
+
+                let mut uvs = vec![[0.0; 2]; positions.len()];
+                if !param::tutte::parameterize(&positions, &local_indices, &mut uvs) {
+                    continue;
+                }
+                // Tutte is only guaranteed to be bijective for disks, charts with handles can
+                // still fold over
+                if !param::flipped_faces(&local_indices, &uvs).is_empty()
+                    || !param::overlapping_faces(&local_indices, &uvs).is_empty()
+                {
+                    continue;
+                }
+
+                // Keep the orientation most of the chart had
+                let mirror = if chart_orientation(&local_indices, &old_uvs) < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                };
+                let texels_per_unit = output.charts[chart_index].texels_per_unit;
+                for uv in uvs.iter_mut() {
+                    *uv = [uv[0] * texels_per_unit * mirror, uv[1] * texels_per_unit];
+                }
+                let fit = fit_chart(&old_uvs, &mut uvs);
+                let page = output.charts[chart_index].atlas_index;
+                let new_triangles = local_triangles(&local_indices, &uvs);
+                if !owners.try_move(
+                    page,
+                    id,
+                    &output.chart_triangles(chart_index),
+                    &new_triangles,
+                ) {
+                    continue;
+                }
+                output.write_chart(chart_index, &local_indices, &uvs);
+                let chart = &mut output.charts[chart_index];
+                chart.texels_per_unit *= fit;
+
+                repairs.push(ChartRepair {
+                    mesh_index: mesh_index as u32,
+                    chart_index: chart_index as u32,
+                    flipped_faces: flipped.len() as u32,
+                    overlapping_faces: overlapping.len() as u32,
+                    texels_per_unit: chart.texels_per_unit,
+                });
+            }
+        }
+
+        repairs
+    }
+
+    // Refines the output charts with `param::arap`, which works for any parameterizer including
+    // the built-in one of xatlas. Like `repair_charts` this keeps every chart within its previous
+    // bounds, charts that grow are shrunk and their texel density lowered to match; repacking
+    // with a Rust strategy afterwards lets them take their full size again. Charts with flipped
+    // faces, and charts that would end up overlapping themselves or closer to another chart than
+    // they were, are left as they were.
+    // Returns the number of charts that changed.
+    pub fn refine_charts(&mut self, options: &param::arap::ArapOptions) -> u32 {
+        let mut refined = 0;
+        let mut owners = self.chart_owners(0, |_, _, _, _, _| {});
+        let mut next_id = 0;
+
+        for (output, input) in self.outputs.iter_mut().zip(&self.inputs) {
+            for chart_index in 0..output.charts.len() {
+                let id = next_id;
+                next_id += 1;
+                let (local_vertices, local_indices) = output.chart_mesh(input, chart_index);
+                let positions: Vec<[f32; 3]> = local_vertices
+                    .iter()
+                    .map(|&i| input.positions[output.vertices[i as usize].xref as usize])
+                    .collect();
+                let old_uvs: Vec<[f32; 2]> = local_vertices
+                    .iter()
+                    .map(|&i| output.vertices[i as usize].uv)
+                    .collect();
+
+                // ARAP wants counter-clockwise triangles in the units of the positions
+                let texels_per_unit = output.charts[chart_index].texels_per_unit;
+                let mirror = if chart_orientation(&local_indices, &old_uvs) < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                };
+                let mut uvs: Vec<[f32; 2]> = old_uvs
+                    .iter()
+                    .map(|uv| [uv[0] * mirror / texels_per_unit, uv[1] / texels_per_unit])
+                    .collect();
+                if !param::flipped_faces(&local_indices, &uvs).is_empty()
+                    || param::arap::refine(&positions, &local_indices, &mut uvs, options) == 0
+                {
+                    continue;
+                }
+                if param::overlapping_faces(&local_indices, &uvs).len()
+                    > param::overlapping_faces(&local_indices, &old_uvs).len()
+                {
+                    continue;
+                }
+
+                for uv in uvs.iter_mut() {
+                    *uv = [uv[0] * texels_per_unit * mirror, uv[1] * texels_per_unit];
+                }
+                let fit = fit_chart(&old_uvs, &mut uvs);
+                let page = output.charts[chart_index].atlas_index;
+                let new_triangles = local_triangles(&local_indices, &uvs);
+                if !owners.try_move(
+                    page,
+                    id,
+                    &output.chart_triangles(chart_index),
+                    &new_triangles,
+                ) {
+                    continue;
+                }
+                output.write_chart(chart_index, &local_indices, &uvs);
+                output.charts[chart_index].texels_per_unit *= fit;
+                refined += 1;
+            }
+        }
+
+        refined
+    }
+
+    // Every input mesh was added as one xatlas mesh per part, stitch those back together
+    fn build_outputs(&mut self) {
         let original_meshes = unsafe {
             std::slice::from_raw_parts((*self.handle).meshes, (*self.handle).meshCount as usize)
         };
+        let texels_per_unit = unsafe { (*self.handle).texelsPerUnit };
+        let mut original_meshes = original_meshes.iter();
 
-        for original_mesh in original_meshes {
-            let mut charts = vec![];
-            let original_charts = unsafe {
-                std::slice::from_raw_parts(
-                    original_mesh.chartArray,
-                    original_mesh.chartCount as usize,
-                )
+        unsafe {
+            self.width = (*self.handle).width;
+            self.height = (*self.handle).height;
+            self.atlas_count = (*self.handle).atlasCount;
+            self.utilization = if (*self.handle).utilization.is_null() {
+                vec![]
+            } else {
+                std::slice::from_raw_parts((*self.handle).utilization, self.atlas_count as usize)
+                    .to_vec()
+            };
+        }
+        self.texels_per_unit = texels_per_unit;
+
+        self.outputs.clear();
+        for input in &self.inputs {
+            let mut output = MeshOutput {
+                texels_per_unit: texels_per_unit * input.importance,
+                ..Default::default()
             };
 
-            for original_chart in original_charts {
-                charts.push(Chart {
-                    atlas_index: original_chart.atlasIndex,
-                    indices: unsafe {
+            for (part, original_mesh) in input.parts.iter().zip(&mut original_meshes) {
+                let base_vertex = output.vertices.len() as u32;
+
+                let original_charts = unsafe {
+                    std::slice::from_raw_parts(
+                        original_mesh.chartArray,
+                        original_mesh.chartCount as usize,
+                    )
+                };
+
+                for original_chart in original_charts {
+                    let indices = unsafe {
                         std::slice::from_raw_parts(
                             original_chart.indexArray,
                             original_chart.indexCount as usize,
                         )
-                    },
-                })
-            }
+                    };
 
-            meshes.push(Mesh {
-                indices: unsafe {
+                    output.charts.push(ChartOutput {
+                        atlas_index: original_chart.atlasIndex,
+                        indices: indices.iter().map(|i| i + base_vertex).collect(),
+                        texels_per_unit: texels_per_unit * part.importance,
+                    });
+                }
+
+                let indices = unsafe {
                     std::slice::from_raw_parts(
                         original_mesh.indexArray,
                         original_mesh.indexCount as usize,
                     )
-                },
-                vertices: unsafe {
+                };
+                output
+                    .indices
+                    .extend(indices.iter().map(|i| i + base_vertex));
+
+                let vertices = unsafe {
                     std::slice::from_raw_parts(
-                        original_mesh.vertexArray as *mut _ as *mut _,
+                        original_mesh.vertexArray,
                         original_mesh.vertexCount as usize,
                     )
-                },
-                charts,
-            });
-        }
+                };
+
+                // Unmatched triangles get u32::MAX, see `Mesh::original_face`
+                let mut faces = FaceLookup::new(input, part);
+                output
+                    .original_face
+                    .extend(indices.chunks_exact(3).map(|t| {
+                        let xref = |i: u32| vertices[i as usize].xref;
+                        faces
+                            .take([xref(t[0]), xref(t[1]), xref(t[2])])
+                            .map(|face| input.input_face(face as usize) as u32)
+                            .unwrap_or(u32::MAX)
+                    }));
+                output.vertices.extend(vertices.iter().map(|v| Vertex {
+                    atlas_index: v.atlasIndex as u32,
+                    uv: v.uv,
+                    xref: part.vertices[v.xref as usize],
+                }));
+            }
 
-        meshes
+            self.outputs.push(output);
+        }
     }
 }
 
-impl<'a> Drop for Xatlas {
+impl Drop for Xatlas {
     fn drop(&mut self) {
         unsafe {
             xatlas::Destroy(self.handle);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{grid, single_chart};
+    use std::mem::ManuallyDrop;
+
+    // `count` separate unit squares as charts of `size` texels, at a density of `size`
+    fn squares(count: u32, size: f32) -> ManuallyDrop<Xatlas> {
+        let mut positions = vec![];
+        let mut uvs = vec![];
+        let mut indices = vec![];
+        let mut charts = vec![];
+        for i in 0..count {
+            let first = positions.len() as u32;
+            for &[x, y] in &[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]] {
+                positions.push([x + i as f32 * 2.0, y, 0.0]);
+                uvs.push([x * size, y * size]);
+            }
+            let chart: Vec<u32> = [0, 1, 2, 0, 2, 3].iter().map(|&c| first + c).collect();
+            indices.extend_from_slice(&chart);
+            charts.push(chart);
+        }
+        let mut atlas = single_chart(positions, indices, &uvs, size);
+        atlas.outputs[0].charts = charts
+            .into_iter()
+            .map(|indices| ChartOutput {
+                atlas_index: 0,
+                indices,
+                texels_per_unit: size,
+            })
+            .collect();
+        atlas
+    }
+
+    // Adds another chart to the single mesh of `atlas`
+    fn add_chart(atlas: &mut Xatlas, positions: &[[f32; 3]], indices: &[u32], uvs: &[[f32; 2]]) {
+        let (input, output) = (&mut atlas.inputs[0], &mut atlas.outputs[0]);
+        let first = input.positions.len() as u32;
+        let indices: Vec<u32> = indices.iter().map(|&i| first + i).collect();
+        input.positions.extend_from_slice(positions);
+        input.indices.extend_from_slice(&indices);
+        output
+            .vertices
+            .extend(uvs.iter().enumerate().map(|(i, &uv)| Vertex {
+                atlas_index: 0,
+                uv,
+                xref: first + i as u32,
+            }));
+        let face = output.original_face.len() as u32;
+        output
+            .original_face
+            .extend(face..face + indices.len() as u32 / 3);
+        output.indices.extend_from_slice(&indices);
+        output.charts.push(ChartOutput {
+            atlas_index: 0,
+            indices,
+            texels_per_unit: output.texels_per_unit,
+        });
+    }
+
+    fn uv_bounds(vertices: &[Vertex]) -> ([f32; 2], [f32; 2]) {
+        vertices
+            .iter()
+            .fold(([f32::MAX; 2], [f32::MIN; 2]), |(min, max), v| {
+                (
+                    [min[0].min(v.uv[0]), min[1].min(v.uv[1])],
+                    [max[0].max(v.uv[0]), max[1].max(v.uv[1])],
+                )
+            })
+    }
+
+    #[test]
+    fn tangents_without_normals() {
+        // A bent sheet facing +z with u along x, the normals come from the output triangles
+        let (positions, indices) = grid(4, |u, _| u * u);
+        let uvs: Vec<[f32; 2]> = positions
+            .iter()
+            .map(|p| [p[0] * 16.0, p[1] * 16.0])
+            .collect();
+        let atlas = single_chart(positions.clone(), indices.clone(), &uvs, 16.0);
+        let mesh = &atlas.meshes()[0];
+
+        let tangents = mesh.compute_tangents(&positions, &[]);
+        let normals = tangent::vertex_normals(&positions, &indices);
+        let expected = mesh.compute_tangents(&positions, &normals);
+        assert_eq!(tangents.indices, indices);
+        assert_eq!(
+            tangents.remap,
+            (0..positions.len() as u32).collect::<Vec<_>>()
+        );
+        assert_eq!(tangents.tangents, expected.tangents);
+        for (t, n) in tangents.tangents.iter().zip(&normals) {
+            assert!(math::dot([t[0], t[1], t[2]], *n).abs() < 1e-5);
+            assert!(t[0] > 0.0 && t[3] == 1.0, "{:?}", t);
+        }
+    }
+
+    #[test]
+    fn refine_charts_stays_in_place() {
+        // A bump projected straight down, mirrored like xatlas may leave a chart
+        let (positions, indices) = grid(8, |u, v| {
+            (1.0 - ((u - 0.5).powi(2) + (v - 0.5).powi(2)) * 4.0).max(0.0)
+        });
+        let uvs: Vec<[f32; 2]> = positions
+            .iter()
+            .map(|p| [40.0 - p[0] * 32.0, 4.0 + p[1] * 32.0])
+            .collect();
+        let mut atlas = single_chart(positions, indices, &uvs, 32.0);
+        let (old_min, old_max) = uv_bounds(&atlas.outputs[0].vertices);
+
+        assert_eq!(atlas.refine_charts(&Default::default()), 1);
+        let mesh = &atlas.meshes()[0];
+        let (min, max) = uv_bounds(mesh.vertices);
+        for c in 0..2 {
+            assert!(min[c] >= old_min[c] - 1e-3 && max[c] <= old_max[c] + 1e-3);
+        }
+        // The bump is larger than its projection, so the chart had to shrink
+        assert!(mesh.charts[0].texels_per_unit < 32.0);
+
+        let uvs: Vec<[f32; 2]> = mesh.vertices.iter().map(|v| v.uv).collect();
+        assert!(chart_orientation(mesh.indices, &uvs) < 0.0);
+        assert!(param::overlapping_faces(mesh.indices, &uvs).is_empty());
+        let mirrored: Vec<[f32; 2]> = uvs.iter().map(|uv| [-uv[0], uv[1]]).collect();
+        assert!(param::flipped_faces(mesh.indices, &mirrored).is_empty());
+    }
+
+    #[test]
+    fn refine_charts_keeps_cuts_open() {
+        // A tube cut open along x = 1, both sides of the cut share their positions
+        let segments = 12;
+        let mut positions = vec![];
+        let mut uvs = vec![];
+        for z in 0..3 {
+            for i in 0..=segments {
+                let a = (i % segments) as f32 / segments as f32 * std::f32::consts::TAU;
+                positions.push([a.cos(), a.sin(), z as f32 * 0.5]);
+                // Stretched along the circumference, so there is something to refine
+                uvs.push([4.0 + i as f32 * 6.0, 4.0 + z as f32 * 4.0]);
+            }
+        }
+        let row = segments + 1;
+        let mut indices = vec![];
+        for z in 0..2 {
+            for i in 0..segments {
+                let a = z * row + i;
+                indices.extend_from_slice(&[a, a + 1, a + row + 1, a, a + row + 1, a + row]);
+            }
+        }
+        let mut atlas = single_chart(positions, indices, &uvs, 8.0);
+
+        assert_eq!(atlas.refine_charts(&Default::default()), 1);
+        let mesh = &atlas.meshes()[0];
+        let uvs: Vec<[f32; 2]> = mesh.vertices.iter().map(|v| v.uv).collect();
+        assert!(param::flipped_faces(mesh.indices, &uvs).is_empty());
+        assert!(param::overlapping_faces(mesh.indices, &uvs).is_empty());
+        for z in 0..3 {
+            let (first, last) = (uvs[(z * row) as usize], uvs[(z * row + segments) as usize]);
+            assert!(last[0] - first[0] > 30.0, "{:?} {:?}", first, last);
+        }
+    }
+
+    #[test]
+    fn refined_charts_keep_clear_of_their_neighbours() {
+        // An L-shaped chart stretched along u, and a small chart three texels away from its corner
+        let (positions, indices) = grid(6, |_, _| 0.0);
+        let indices: Vec<u32> = indices
+            .chunks_exact(3)
+            .filter(|t| {
+                t.iter()
+                    .any(|&i| positions[i as usize][0] < 0.5 || positions[i as usize][1] < 0.5)
+            })
+            .flatten()
+            .cloned()
+            .collect();
+        let uvs: Vec<[f32; 2]> = positions
+            .iter()
+            .map(|p| [4.0 + p[0] * p[0] * 24.0, 4.0 + p[1] * 16.0])
+            .collect();
+        let square = [
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0],
+            [1.0, 1.0, 1.0],
+            [0.0, 1.0, 1.0],
+        ];
+        let square_uvs = [[14.0, 15.0], [27.0, 15.0], [27.0, 19.0], [14.0, 19.0]];
+
+        let mut atlas = single_chart(positions, indices, &uvs, 16.0);
+        add_chart(&mut atlas, &square, &[0, 1, 2, 0, 2, 3], &square_uvs);
+        assert!(atlas.validate_gutters(0, 2).is_empty());
+        atlas.refine_charts(&Default::default());
+        assert!(atlas.validate_gutters(0, 2).is_empty());
+    }
+
+    #[test]
+    fn repair_charts_unfolds() {
+        let (positions, indices) = grid(6, |_, _| 0.0);
+        // Fold the right third of the chart back over the middle
+        let uvs: Vec<[f32; 2]> = positions
+            .iter()
+            .map(|p| {
+                let u = if p[0] > 0.6 { 1.2 - p[0] } else { p[0] };
+                [4.0 + u * 16.0, 4.0 + p[1] * 16.0]
+            })
+            .collect();
+        let mut atlas = single_chart(positions.clone(), indices.clone(), &uvs, 16.0);
+        let repairs = atlas.repair_charts();
+        assert_eq!(repairs.len(), 1);
+        assert!(repairs[0].flipped_faces > 0);
+        assert!(repairs[0].texels_per_unit < 16.0);
+
+        let mesh = &atlas.meshes()[0];
+        assert_eq!(mesh.charts[0].texels_per_unit, repairs[0].texels_per_unit);
+        let uvs: Vec<[f32; 2]> = mesh.vertices.iter().map(|v| v.uv).collect();
+        assert!(param::flipped_faces(mesh.indices, &uvs).is_empty());
+        assert!(param::overlapping_faces(mesh.indices, &uvs).is_empty());
+
+        // Valid charts are left alone
+        let uvs: Vec<[f32; 2]> = positions
+            .iter()
+            .map(|p| [p[0] * 16.0, p[1] * 16.0])
+            .collect();
+        let mut atlas = single_chart(positions, indices, &uvs, 16.0);
+        assert!(atlas.repair_charts().is_empty());
+        assert_eq!(atlas.meshes()[0].vertices[7].uv, uvs[7]);
+    }
+
+    #[test]
+    fn repaired_charts_keep_clear_of_their_neighbours() {
+        // An L-shaped chart with a fold in its lower arm, and a small chart in the corner of its
+        // bounds that the L leaves free
+        let (positions, indices) = grid(6, |_, _| 0.0);
+        let indices: Vec<u32> = indices
+            .chunks_exact(3)
+            .filter(|t| {
+                t.iter()
+                    .any(|&i| positions[i as usize][0] < 0.5 || positions[i as usize][1] < 0.5)
+            })
+            .flatten()
+            .cloned()
+            .collect();
+        let uvs: Vec<[f32; 2]> = positions
+            .iter()
+            .map(|p| {
+                let u = if p[0] > 0.8 { 1.6 - p[0] } else { p[0] };
+                [4.0 + u * 16.0, 4.0 + p[1] * 16.0]
+            })
+            .collect();
+        let square = [
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0],
+            [1.0, 1.0, 1.0],
+            [0.0, 1.0, 1.0],
+        ];
+        let square_uvs = [[14.0, 15.0], [16.0, 15.0], [16.0, 19.0], [14.0, 19.0]];
+
+        let mut atlas = single_chart(positions.clone(), indices.clone(), &uvs, 16.0);
+        assert_eq!(atlas.repair_charts().len(), 1);
+
+        let mut atlas = single_chart(positions, indices, &uvs, 16.0);
+        add_chart(&mut atlas, &square, &[0, 1, 2, 0, 2, 3], &square_uvs);
+        let gutters = atlas.validate_gutters(0, 1).len();
+        assert!(atlas.repair_charts().is_empty());
+        assert_eq!(atlas.meshes()[0].vertices[7].uv, uvs[7]);
+        assert_eq!(atlas.validate_gutters(0, 1).len(), gutters);
+    }
+
+    #[test]
+    fn page_overflow() {
+        let options = |overflow| PackOptions {
+            strategy: pack::PackStrategy::Skyline,
+            resolution: 32,
+            max_pages: 1,
+            overflow,
+            ..Default::default()
+        };
+
+        let mut atlas = squares(8, 15.0);
+        atlas
+            .finish_packing(&options(PageOverflow::SpillToNewPage))
+            .unwrap();
+        assert_eq!(atlas.atlas_count(), 2);
+
+        let error = atlas.finish_packing(&options(PageOverflow::Error));
+        assert_eq!(
+            error,
+            Err(PackError::TooManyPages {
+                pages: 2,
+                max_pages: 1
+            })
+        );
+
+        atlas
+            .finish_packing(&options(PageOverflow::ShrinkDensity))
+            .unwrap();
+        assert_eq!(atlas.atlas_count(), 1);
+        assert!(atlas.texels_per_unit() < 15.0);
+        assert_eq!([atlas.width(), atlas.height()], [32, 32]);
+        assert_eq!(atlas.utilization().len(), 1);
+    }
+
+    #[test]
+    fn non_square_pages_fall_back_from_xatlas() {
+        let options = |max_width, max_height, aspect_ratio| PackOptions {
+            max_width,
+            max_height,
+            aspect_ratio,
+            ..Default::default()
+        };
+        assert_eq!(options(0, 0, 0.0).packer(), pack::PackStrategy::Xatlas);
+        assert_eq!(options(64, 64, 2.0).packer(), pack::PackStrategy::Xatlas);
+        assert_eq!(options(0, 64, 1.0).packer(), pack::PackStrategy::Xatlas);
+        assert_eq!(options(64, 32, 0.0).packer(), pack::PackStrategy::Skyline);
+        assert_eq!(options(0, 0, 2.0).packer(), pack::PackStrategy::Skyline);
+
+        let mut atlas = squares(16, 15.0);
+        atlas.finish_packing(&options(0, 0, 2.0)).unwrap();
+        assert_eq!(atlas.atlas_count(), 1);
+        let ratio = atlas.width() as f32 / atlas.height() as f32;
+        assert!(ratio > 1.5 && ratio < 3.0, "{}", ratio);
+
+        let mut atlas = squares(8, 15.0);
+        atlas.finish_packing(&options(64, 32, 0.0)).unwrap();
+        assert_eq!(atlas.atlas_count(), 1);
+        assert!(atlas.width() <= 64 && atlas.height() <= 32);
+        assert_eq!(atlas.texels_per_unit(), 15.0);
+    }
+
+    #[test]
+    fn charts_larger_than_the_resolution_shrink() {
+        let mut atlas = squares(1, 50.0);
+        let options = PackOptions {
+            resolution: 32,
+            strategy: pack::PackStrategy::Skyline,
+            ..Default::default()
+        };
+        atlas.finish_packing(&options).unwrap();
+        assert_eq!([atlas.width(), atlas.height()], [32, 32]);
+        let (_, max) = uv_bounds(&atlas.outputs[0].vertices);
+        assert!(max[0] <= 32.0 && max[1] <= 32.0, "{:?}", max);
+        assert!(atlas.texels_per_unit() <= 32.0);
+    }
+
+    #[test]
+    fn gutters_shrink_with_the_mip_level() {
+        // Two squares three texels apart, which is a gap of two texels in mip 1
+        let square = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let uvs = |x: f32| [[x, 4.5], [x + 7.0, 4.5], [x + 7.0, 11.5], [x, 11.5]];
+        let indices = [0, 1, 2, 0, 2, 3];
+        let mut atlas = single_chart(square.to_vec(), indices.to_vec(), &uvs(4.5), 7.0);
+        add_chart(&mut atlas, &square, &indices, &uvs(14.5));
+
+        assert!(atlas.validate_gutters(0, 2).is_empty());
+        assert!(atlas.validate_gutters(1, 1).is_empty());
+        let violations = atlas.validate_gutters(1, 2);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].atlas_index, 0);
+        assert_eq!(violations[0].charts, [(0, 0), (0, 1)]);
+        assert_eq!(violations[0].texel[0], 5);
+
+        // Levels past the size of the atlas leave a single texel, shared by both charts
+        assert_eq!(atlas.validate_gutters(40, 0).len(), 1);
+        let options = PackOptions {
+            padding: 1,
+            padding_mip_level: 40,
+            ..Default::default()
+        };
+        assert_eq!(options.texel_padding(), 1 << 16);
+        assert_eq!(options.alignment(), [1 << 16, 1 << 16]);
+    }
+
+    #[test]
+    fn block_sizes_xatlas_cant_align_to_fall_back() {
+        let options = |block_size, padding_mip_level| PackOptions {
+            padding: 1,
+            block_align: true,
+            block_size,
+            padding_mip_level,
+            ..Default::default()
+        };
+        assert_eq!(options([4, 4], 0).packer(), pack::PackStrategy::Xatlas);
+        assert_eq!(options([6, 6], 0).packer(), pack::PackStrategy::Skyline);
+        assert_eq!(options([4, 4], 2).packer(), pack::PackStrategy::Skyline);
+
+        let mut atlas = squares(10, 13.0);
+        atlas.finish_packing(&options([6, 6], 1)).unwrap();
+        assert_eq!([atlas.width() % 6, atlas.height() % 6], [0, 0]);
+        let mesh = &atlas.meshes()[0];
+        for chart in &mesh.charts {
+            // Every chart starts on the 6x6 grid, one texel of padding at mip 1 away
+            let min = chart.indices.iter().fold([f32::MAX; 2], |min, &i| {
+                let uv = mesh.vertices[i as usize].uv;
+                [min[0].min(uv[0]), min[1].min(uv[1])]
+            });
+            assert_eq!(
+                [(min[0] - 2.0) % 6.0, (min[1] - 2.0) % 6.0],
+                [0.0, 0.0],
+                "{:?}",
+                min
+            );
+        }
+    }
+}
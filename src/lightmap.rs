@@ -0,0 +1,299 @@
+// Lightmaps for checking unwraps end to end without an engine: direct lighting plus one bounce of
+// indirect light, ray traced against the input meshes. Values are irradiance, lights and albedo
+// use whatever linear units the caller picks.
+
+use crate::bake::bvh::Bvh;
+use crate::bake::{
+    cosine_direction, input_point, sample_page, sample_points, scene_bvh, surface_point, Image,
+    SurfacePoint,
+};
+use crate::input::InputMesh;
+use crate::math::*;
+use crate::Xatlas;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Light {
+    // Radiant intensity `color` in every direction
+    Point {
+        position: [f32; 3],
+        color: [f32; 3],
+    },
+    // Irradiance `color` on surfaces facing the light, `direction` is the one the light travels in
+    Directional {
+        direction: [f32; 3],
+        color: [f32; 3],
+    },
+    // Parallelogram spanned by `edges` from `corner` that emits radiance `color` to the side of
+    // cross(edges[0], edges[1])
+    Area {
+        corner: [f32; 3],
+        edges: [[f32; 3]; 2],
+        color: [f32; 3],
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct LightmapOptions {
+    pub light_samples: u32,    // shadow rays per area light and texel
+    pub indirect_samples: u32, // bounce rays per texel, 0 for direct lighting only
+    pub albedo: [f32; 3],      // reflectance of all surfaces for the bounce
+}
+
+impl Default for LightmapOptions {
+    fn default() -> Self {
+        LightmapOptions {
+            light_samples: 16,
+            indirect_samples: 64,
+            albedo: [0.5; 3],
+        }
+    }
+}
+
+struct Scene<'a> {
+    inputs: &'a [InputMesh],
+    first_triangle: Vec<usize>, // of every input mesh in the bvh
+    bvh: Bvh,
+    lights: &'a [Light],
+}
+
+impl<'a> Scene<'a> {
+    // Irradiance arriving at `point` straight from the lights
+    fn direct(&self, point: &SurfacePoint, light_samples: u32, seed: u32) -> Vec3 {
+        let origin = add(
+            point.position,
+            scale(point.face_normal, self.bvh.ray_offset()),
+        );
+        let mut irradiance = [0.0; 3];
+
+        for light in self.lights {
+            match *light {
+                Light::Point { position, color } => {
+                    let to_light = sub(position, origin);
+                    let distance = length(to_light);
+                    let dir = normalize(to_light);
+                    let cos = dot(point.normal, dir);
+                    if cos > 0.0 && !self.bvh.occluded(origin, dir, distance) {
+                        irradiance = add(irradiance, scale(color, cos / (distance * distance)));
+                    }
+                }
+                Light::Directional { direction, color } => {
+                    let dir = scale(normalize(direction), -1.0);
+                    let cos = dot(point.normal, dir);
+                    if cos > 0.0 && !self.bvh.occluded(origin, dir, f32::INFINITY) {
+                        irradiance = add(irradiance, scale(color, cos));
+                    }
+                }
+                Light::Area {
+                    corner,
+                    edges,
+                    color,
+                } => {
+                    let light_normal = cross(edges[0], edges[1]);
+                    let area = length(light_normal);
+                    let light_normal = normalize(light_normal);
+                    let samples = light_samples.max(1);
+
+                    let mut sum = 0.0;
+                    for u in sample_points(seed, samples) {
+                        let p = add(corner, add(scale(edges[0], u[0]), scale(edges[1], u[1])));
+                        let to_light = sub(p, origin);
+                        let distance = length(to_light);
+                        let dir = normalize(to_light);
+                        let cos = dot(point.normal, dir);
+                        let cos_light = -dot(light_normal, dir);
+                        // Stop short of the light, it may be part of the meshes
+                        let t_max = distance - self.bvh.ray_offset();
+                        if cos > 0.0 && cos_light > 0.0 && !self.bvh.occluded(origin, dir, t_max) {
+                            sum += cos * cos_light / (distance * distance);
+                        }
+                    }
+                    irradiance = add(irradiance, scale(color, area * sum / samples as f32));
+                }
+            }
+        }
+        irradiance
+    }
+
+    // Irradiance at `point` from the directly lit surfaces around it
+    fn indirect(&self, point: &SurfacePoint, options: &LightmapOptions, seed: u32) -> Vec3 {
+        let origin = add(
+            point.position,
+            scale(point.face_normal, self.bvh.ray_offset()),
+        );
+        let samples = options.indirect_samples;
+        let mut irradiance = [0.0; 3];
+
+        for (s, u) in sample_points(seed, samples).enumerate() {
+            let dir = cosine_direction(point.normal, u);
+            let hit = match self.bvh.intersect(origin, dir, f32::INFINITY) {
+                Some(hit) => hit,
+                None => continue,
+            };
+
+            let triangle = hit.triangle as usize;
+            let mesh = self
+                .first_triangle
+                .partition_point(|&first| first <= triangle)
+                - 1;
+            let input = &self.inputs[mesh];
+            let face = triangle - self.first_triangle[mesh];
+            let t = &input.indices[face * 3..face * 3 + 3];
+            let mut hit_point = input_point(input, [t[0], t[1], t[2]], hit.barycentrics);
+
+            // Surfaces are two sided, the bounce leaves from the side the ray came from
+            if dot(hit_point.face_normal, dir) > 0.0 {
+                hit_point.normal = scale(hit_point.normal, -1.0);
+                hit_point.face_normal = scale(hit_point.face_normal, -1.0);
+            }
+
+            let seed = seed.wrapping_mul(samples).wrapping_add(s as u32);
+            irradiance = add(irradiance, self.direct(&hit_point, 1, seed));
+        }
+
+        // With cosine distributed rays the estimate of the irradiance is the mean incoming
+        // radiance times pi, which cancels against the 1 / pi of the diffuse bounce
+        let s = 1.0 / samples.max(1) as f32;
+        [
+            irradiance[0] * options.albedo[0] * s,
+            irradiance[1] * options.albedo[1] * s,
+            irradiance[2] * options.albedo[2] * s,
+        ]
+    }
+}
+
+// A lightmap for every atlas page, texels outside of the charts are 0
+pub fn bake(atlas: &Xatlas, lights: &[Light], options: &LightmapOptions) -> Vec<Image<[f32; 3]>> {
+    let mut first_triangle = vec![];
+    let mut triangles = 0;
+    for input in &atlas.inputs {
+        first_triangle.push(triangles);
+        triangles += input.face_count();
+    }
+    let scene = Scene {
+        inputs: &atlas.inputs,
+        first_triangle,
+        bvh: scene_bvh(&atlas.inputs),
+        lights,
+    };
+
+    let meshes = atlas.meshes();
+    let size = [atlas.width(), atlas.height()];
+    (0..atlas.atlas_count())
+        .map(|page| {
+            let mut image = Image::new(size[0], size[1]);
+            for (i, texel) in sample_page(&meshes, page, size).iter().enumerate() {
+                let texel = match texel {
+                    Some(texel) => texel,
+                    None => continue,
+                };
+                let point = surface_point(&meshes[texel.mesh], &atlas.inputs[texel.mesh], texel);
+                let seed = page
+                    .wrapping_mul(size[0])
+                    .wrapping_mul(size[1])
+                    .wrapping_add(i as u32);
+
+                let direct = scene.direct(&point, options.light_samples, seed);
+                let indirect = if options.indirect_samples > 0 {
+                    scene.indirect(&point, options, seed)
+                } else {
+                    [0.0; 3]
+                };
+                image.data[i] = add(direct, indirect);
+            }
+            image
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bake::chart_mask;
+    use crate::fixtures::single_chart;
+
+    // Adds a quad that occludes and reflects light but isn't part of any chart
+    fn add_quad(atlas: &mut Xatlas, corners: [[f32; 3]; 4]) {
+        let input = &mut atlas.inputs[0];
+        let first = input.positions.len() as u32;
+        input.positions.extend_from_slice(&corners);
+        input
+            .indices
+            .extend([0, 1, 2, 0, 2, 3].iter().map(|&c| first + c));
+    }
+
+    #[test]
+    fn direct_light_shadows_and_bounce() {
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let uvs = [[0.0, 0.0], [16.0, 0.0], [16.0, 16.0], [0.0, 16.0]];
+        let plane = || single_chart(positions.clone(), vec![0, 1, 2, 0, 2, 3], &uvs, 16.0);
+        let mask = chart_mask(&plane(), 0);
+
+        let color = [2.0, 1.0, 0.5];
+        let lights = [Light::Directional {
+            direction: [1.0, 0.0, -1.0],
+            color,
+        }];
+        let direct_only = LightmapOptions {
+            indirect_samples: 0,
+            ..Default::default()
+        };
+        let expect = |image: &Image<[f32; 3]>, lit: Vec3| {
+            for (&texel, &covered) in image.data.iter().zip(&mask.data) {
+                let expected = if covered { lit } else { [0.0; 3] };
+                assert!(
+                    length(sub(texel, expected)) < 1e-4,
+                    "{:?} {:?}",
+                    texel,
+                    expected
+                );
+            }
+        };
+
+        // Irradiance of a plane is the color times the cosine to the light
+        let lit = scale(color, std::f32::consts::FRAC_1_SQRT_2);
+        let images = bake(&plane(), &lights, &direct_only);
+        assert_eq!(images.len(), 1);
+        expect(&images[0], lit);
+        // Nothing around to bounce off
+        expect(&bake(&plane(), &lights, &Default::default())[0], lit);
+
+        // A wall past the plane that faces the light bounces some of it back
+        let mut walled = plane();
+        add_quad(
+            &mut walled,
+            [
+                [1.5, -5.0, 0.0],
+                [1.5, 5.0, 0.0],
+                [1.5, 5.0, 3.0],
+                [1.5, -5.0, 3.0],
+            ],
+        );
+        expect(&bake(&walled, &lights, &direct_only)[0], lit);
+        let bounced = bake(&walled, &lights, &Default::default());
+        for (&texel, &covered) in bounced[0].data.iter().zip(&mask.data) {
+            if covered {
+                for c in 0..3 {
+                    assert!(texel[c] > lit[c], "{:?} {:?}", texel, lit);
+                }
+            }
+        }
+
+        // A second plane between the first one and the light
+        let mut shadowed = plane();
+        add_quad(
+            &mut shadowed,
+            [
+                [-2.0, -1.0, 1.0],
+                [1.0, -1.0, 1.0],
+                [1.0, 2.0, 1.0],
+                [-2.0, 2.0, 1.0],
+            ],
+        );
+        expect(&bake(&shadowed, &lights, &direct_only)[0], [0.0; 3]);
+    }
+}
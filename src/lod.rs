@@ -0,0 +1,199 @@
+// Lightmap uvs for lower levels of detail that share the atlas of the unwrapped LOD0. Every LOD
+// face is assigned the LOD0 chart closest to its centroid and its vertices take the uvs of the
+// closest point on that chart, so LOD vertices are split wherever their faces land in different
+// charts.
+
+use crate::bake::bvh::Bvh;
+use crate::input::triangulate;
+use crate::math::*;
+use crate::{AddMeshError, MeshDecl, Vertex, Xatlas};
+
+use std::collections::HashMap;
+
+#[derive(Debug, Default, Clone)]
+pub struct LodMesh {
+    pub indices: Vec<u32>,       // triangles, polygons of the LOD are triangulated
+    pub vertices: Vec<Vertex>,   // `xref` is the LOD vertex
+    pub original_face: Vec<u32>, // LOD face of every triangle
+}
+
+// Transfers the uvs of the mesh at `lod0` in `atlas` onto `lod`, which should cover the same
+// surface. The closest point is used without regard for normals, so thin features of LOD0 may
+// hand their uvs to the wrong side.
+pub fn transfer_uvs(atlas: &Xatlas, lod0: usize, lod: &MeshDecl) -> Result<LodMesh, AddMeshError> {
+    let positions = lod.decode_positions()?;
+    let face_indices = lod.decode_indices()?;
+    let face_sizes = lod.decode_face_sizes(&face_indices)?;
+
+    let meshes = atlas.meshes();
+    let mesh = &meshes[lod0];
+    let input = &atlas.inputs[lod0];
+
+    // Chart of every output vertex, output vertices are never shared between charts
+    let mut vertex_chart = vec![u32::MAX; mesh.vertices.len()];
+    for (chart_index, chart) in mesh.charts.iter().enumerate() {
+        for &v in chart.indices {
+            vertex_chart[v as usize] = chart_index as u32;
+        }
+    }
+    let face_chart: Vec<u32> = mesh
+        .indices
+        .chunks_exact(3)
+        .map(|t| vertex_chart[t[0] as usize])
+        .collect();
+
+    let triangles: Vec<[Vec3; 3]> = mesh
+        .indices
+        .chunks_exact(3)
+        .map(|t| {
+            let p = |c: usize| input.positions[mesh.vertices[t[c] as usize].xref as usize];
+            [p(0), p(1), p(2)]
+        })
+        .collect();
+    let bvh = Bvh::new(&triangles);
+
+    let mut lod_mesh = LodMesh::default();
+    let mut split: HashMap<(u32, u32), u32> = HashMap::new(); // (LOD vertex, chart) -> vertex
+    let mut first = 0;
+    for (face, &size) in face_sizes.iter().enumerate() {
+        let polygon = &face_indices[first..first + size];
+        first += size;
+
+        for t in triangulate(&positions, polygon).chunks_exact(3) {
+            let centroid = scale(
+                add(
+                    add(positions[t[0] as usize], positions[t[1] as usize]),
+                    positions[t[2] as usize],
+                ),
+                1.0 / 3.0,
+            );
+            let chart = match bvh.closest(centroid, |_| true) {
+                Some(hit) => face_chart[hit.triangle as usize],
+                None => continue,
+            };
+
+            for &v in t {
+                let index = *split.entry((v, chart)).or_insert_with(|| {
+                    let p = positions[v as usize];
+                    let hit = bvh
+                        .closest(p, |triangle| face_chart[triangle as usize] == chart)
+                        .unwrap();
+                    let corners =
+                        &mesh.indices[hit.triangle as usize * 3..hit.triangle as usize * 3 + 3];
+                    let mut uv = [0.0; 2];
+                    for (&corner, &b) in corners.iter().zip(&hit.barycentrics) {
+                        let corner_uv = mesh.vertices[corner as usize].uv;
+                        uv = [uv[0] + corner_uv[0] * b, uv[1] + corner_uv[1] * b];
+                    }
+
+                    lod_mesh.vertices.push(Vertex {
+                        atlas_index: mesh.vertices[corners[0] as usize].atlas_index,
+                        uv,
+                        xref: v,
+                    });
+                    lod_mesh.vertices.len() as u32 - 1
+                });
+                lod_mesh.indices.push(index);
+            }
+            lod_mesh.original_face.push(face as u32);
+        }
+    }
+
+    Ok(lod_mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{decl, grid, single_chart};
+    use crate::ChartOutput;
+
+    #[test]
+    fn lod0_keeps_its_uvs() {
+        let (positions, indices) = grid(4, |u, v| u * v);
+        let uvs: Vec<[f32; 2]> = positions
+            .iter()
+            .map(|p| [p[0] * 16.0, p[1] * 16.0])
+            .collect();
+        let atlas = single_chart(positions.clone(), indices.clone(), &uvs, 16.0);
+
+        let lod = transfer_uvs(&atlas, 0, &decl(&positions, &indices)).unwrap();
+        assert_eq!(lod.vertices.len(), positions.len());
+        assert_eq!(
+            lod.original_face,
+            (0..indices.len() as u32 / 3).collect::<Vec<_>>()
+        );
+        for (&i, &original) in lod.indices.iter().zip(&indices) {
+            let v = lod.vertices[i as usize];
+            assert_eq!((v.xref, v.atlas_index), (original, 0));
+            let uv = uvs[original as usize];
+            assert!((v.uv[0] - uv[0]).abs() < 1e-4 && (v.uv[1] - uv[1]).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn faces_across_a_chart_boundary_split_their_vertices() {
+        // LOD0 is the unit square cut in two charts at x = 0.5, the right one moved along u
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [0.5, 0.0, 0.0],
+            [0.5, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.5, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.5, 1.0, 0.0],
+        ];
+        let uvs: Vec<[f32; 2]> = positions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| [p[0] * 16.0 + if i < 4 { 0.0 } else { 20.0 }, p[1] * 16.0])
+            .collect();
+        let indices = vec![0, 1, 2, 0, 2, 3, 4, 5, 6, 4, 6, 7];
+        let mut atlas = single_chart(positions, indices.clone(), &uvs, 16.0);
+        atlas.outputs[0].charts = indices
+            .chunks_exact(6)
+            .map(|chart| ChartOutput {
+                atlas_index: 0,
+                indices: chart.to_vec(),
+                texels_per_unit: 16.0,
+            })
+            .collect();
+
+        // The LOD cuts the square along the other way, its first face lands on the right chart
+        // and its second on the left one
+        let lod_positions = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let lod_indices = [0, 1, 2, 0, 2, 3];
+        let lod = transfer_uvs(&atlas, 0, &decl(&lod_positions, &lod_indices)).unwrap();
+        assert_eq!(lod.original_face, vec![0, 1]);
+        assert_eq!(lod.vertices.len(), 6);
+
+        let corners: Vec<(u32, [f32; 2])> = lod
+            .indices
+            .iter()
+            .map(|&i| (lod.vertices[i as usize].xref, lod.vertices[i as usize].uv))
+            .collect();
+        let expected = [
+            (0, [28.0, 0.0]),
+            (1, [36.0, 0.0]),
+            (2, [36.0, 16.0]),
+            (0, [0.0, 0.0]),
+            (2, [8.0, 16.0]),
+            (3, [0.0, 16.0]),
+        ];
+        for (&(xref, uv), &(expected_xref, expected_uv)) in corners.iter().zip(&expected) {
+            assert_eq!(xref, expected_xref);
+            assert!(
+                length2(sub2(uv, expected_uv)) < 1e-4,
+                "{:?} {:?}",
+                uv,
+                expected_uv
+            );
+        }
+    }
+}
@@ -0,0 +1,74 @@
+// Small vector helpers shared by the Rust side of the pipeline
+
+pub(crate) type Vec2 = [f32; 2];
+pub(crate) type Vec3 = [f32; 3];
+
+pub(crate) fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+pub(crate) fn add(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+pub(crate) fn scale(a: Vec3, s: f32) -> Vec3 {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+
+pub(crate) fn dot(a: Vec3, b: Vec3) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(crate) fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+pub(crate) fn length(a: Vec3) -> f32 {
+    dot(a, a).sqrt()
+}
+
+pub(crate) fn normalize(a: Vec3) -> Vec3 {
+    let l = length(a);
+    if l > 0.0 {
+        scale(a, 1.0 / l)
+    } else {
+        a
+    }
+}
+
+pub(crate) fn sub2(a: Vec2, b: Vec2) -> Vec2 {
+    [a[0] - b[0], a[1] - b[1]]
+}
+
+pub(crate) fn length2(a: Vec2) -> f32 {
+    (a[0] * a[0] + a[1] * a[1]).sqrt()
+}
+
+pub(crate) fn cross2(a: Vec2, b: Vec2) -> f32 {
+    a[0] * b[1] - a[1] * b[0]
+}
+
+// Twice the signed area, positive for counter clockwise triangles
+pub(crate) fn signed_area2(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    cross2(sub2(b, a), sub2(c, a))
+}
+
+pub(crate) fn triangle_area(a: Vec3, b: Vec3, c: Vec3) -> f32 {
+    length(cross(sub(b, a), sub(c, a))) * 0.5
+}
+
+// Any orthonormal pair spanning the plane perpendicular to `n`
+pub(crate) fn tangent_frame(n: Vec3) -> (Vec3, Vec3) {
+    let n = normalize(n);
+    let helper = if n[0].abs() < 0.9 {
+        [1.0, 0.0, 0.0]
+    } else {
+        [0.0, 1.0, 0.0]
+    };
+    let t = normalize(cross(helper, n));
+    (t, cross(n, t))
+}
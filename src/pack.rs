@@ -0,0 +1,623 @@
+// Chart packing implemented in Rust. Charts are packed by their (optionally rotated) bounding
+// rectangles, which is a lot faster than the brute force rasterized packing of xatlas on large
+// chart counts at the cost of some wasted space around irregular charts.
+
+use crate::PackOptions;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PackStrategy {
+    #[default]
+    Xatlas, // xatlas' `PackCharts`, square pages only
+    Skyline, // bottom left placement on a skyline, fast and good for charts of similar height
+    MaxRects, // best short side fit into the free rectangles, slower but tighter
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PackRotation {
+    #[default]
+    None,
+    Quarter,   // charts may be rotated by 90 degrees
+    Arbitrary, // charts are first turned to their minimum area bounding rectangle
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Placement {
+    pub atlas_index: u32,
+    pub x: u32,
+    pub y: u32,
+    pub rotated: bool, // by 90 degrees, the rect then occupies height x width
+}
+
+#[derive(Debug, Default)]
+pub struct Packing {
+    pub placements: Vec<Placement>,
+    pub width: u32,
+    pub height: u32,
+    pub atlas_count: u32,
+}
+
+// Packs rectangles onto pages of at most `page_size`, where a zero size is unbounded. Unless both
+// are bounded the page width is picked from the total area to get close to `aspect_ratio` (width
+// over height). Rects go onto as many pages as needed and one that doesn't fit on an empty page
+// gets a page of its own. The resulting width and height are those of the area that is in use.
+pub fn pack_rects(
+    sizes: &[[u32; 2]],
+    strategy: PackStrategy,
+    rotate: bool,
+    page_size: [u32; 2],
+    aspect_ratio: f32,
+) -> Packing {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    if rotate {
+        order.sort_by_key(|&i| {
+            let [w, h] = sizes[i];
+            std::cmp::Reverse((w.max(h), w.min(h)))
+        });
+    } else {
+        order.sort_by_key(|&i| std::cmp::Reverse((sizes[i][1], sizes[i][0])));
+    }
+
+    let page_size = if page_size[0] > 0 && page_size[1] > 0 {
+        page_size
+    } else {
+        // Width from the total area, below the width limit and enough to stay within the height limit
+        let area = sizes.iter().map(|s| s[0] as f64 * s[1] as f64).sum::<f64>() / 0.85;
+        let aspect_ratio = if aspect_ratio > 0.0 {
+            aspect_ratio as f64
+        } else {
+            1.0
+        };
+        let mut width = (area * aspect_ratio).sqrt();
+        if page_size[1] > 0 {
+            width = width.max(area / page_size[1] as f64);
+        }
+        let widest = sizes
+            .iter()
+            .map(|s| if rotate { s[0].min(s[1]) } else { s[0] })
+            .max()
+            .unwrap_or(1);
+        let mut width = (width.ceil() as u32).max(widest).max(1);
+        if page_size[0] > 0 {
+            width = width.min(page_size[0]);
+        }
+        let height = if page_size[1] > 0 {
+            page_size[1]
+        } else {
+            u32::MAX
+        };
+        [width, height]
+    };
+
+    let new_bin = || match strategy {
+        PackStrategy::MaxRects => Bin::MaxRects(vec![[0, 0, page_size[0], page_size[1]]]),
+        _ => Bin::Skyline(vec![[0, 0, page_size[0]]]),
+    };
+
+    let mut bins = vec![];
+    let mut packing = Packing {
+        placements: vec![Placement::default(); sizes.len()],
+        ..Default::default()
+    };
+    for i in order {
+        let size = sizes[i];
+        let placed = bins
+            .iter_mut()
+            .enumerate()
+            .find_map(|(page, bin): (usize, &mut Bin)| {
+                bin.insert(size, rotate, page_size)
+                    .map(|(x, y, rotated)| Placement {
+                        atlas_index: page as u32,
+                        x,
+                        y,
+                        rotated,
+                    })
+            });
+
+        let placement = match placed {
+            Some(placement) => placement,
+            None => {
+                let mut bin = new_bin();
+                let (x, y, rotated) = match bin.insert(size, rotate, page_size) {
+                    Some(placement) => placement,
+                    None => {
+                        // Too large for a page, it gets one to itself
+                        bin = Bin::Full;
+                        (0, 0, false)
+                    }
+                };
+                bins.push(bin);
+                Placement {
+                    atlas_index: bins.len() as u32 - 1,
+                    x,
+                    y,
+                    rotated,
+                }
+            }
+        };
+
+        let (w, h) = if placement.rotated {
+            (size[1], size[0])
+        } else {
+            (size[0], size[1])
+        };
+        packing.width = packing.width.max(placement.x + w);
+        packing.height = packing.height.max(placement.y + h);
+        packing.placements[i] = placement;
+    }
+
+    packing.atlas_count = bins.len() as u32;
+    packing
+}
+
+// Packs charts given by the texel space positions of their vertices, which are moved to their
+// place in the atlas. Charts are padded by `padding` texels of `padding_mip_level`, and sized and
+// placed on the grid of that mip level's texels and of `block_size` with `block_align`. Pages are `resolution` in size or as small as possible within
+// `max_width` and `max_height`, rounded up to a power of two with `power_of_two`.
+// `PackStrategy::Xatlas` falls back to the skyline packer here as the xatlas packer only works
+// on the charts of an atlas.
+pub fn pack_charts(charts: &mut [Vec<[f32; 2]>], options: &PackOptions) -> Packing {
+    let align = options.alignment();
+    let padding = options.texel_padding();
+
+    let mut sizes = vec![];
+    for uvs in charts.iter_mut() {
+        if options.rotation == PackRotation::Arbitrary {
+            let angle = min_area_rect_angle(uvs);
+            let (sin, cos) = (-angle).sin_cos();
+            for uv in uvs.iter_mut() {
+                *uv = [uv[0] * cos - uv[1] * sin, uv[0] * sin + uv[1] * cos];
+            }
+        }
+
+        let (min, max) = bounds(uvs);
+        for uv in uvs.iter_mut() {
+            *uv = [
+                uv[0] - min[0] + padding as f32,
+                uv[1] - min[1] + padding as f32,
+            ];
+        }
+        let size = |extent: f32, align: u32| {
+            let size = extent.max(0.0).ceil() as u32 + 1 + padding * 2;
+            size.div_ceil(align) * align
+        };
+        sizes.push([
+            size(max[0] - min[0], align[0]),
+            size(max[1] - min[1], align[1]),
+        ]);
+    }
+
+    let strategy = match options.strategy {
+        PackStrategy::Xatlas => PackStrategy::Skyline,
+        strategy => strategy,
+    };
+    let mut packing = pack_rects(
+        &sizes,
+        strategy,
+        options.rotation != PackRotation::None,
+        options.size_limits(),
+        options.aspect_ratio,
+    );
+    let [width, height] = options.atlas_size([packing.width, packing.height]);
+    packing.width = width;
+    packing.height = height;
+
+    for ((uvs, placement), size) in charts.iter_mut().zip(&packing.placements).zip(&sizes) {
+        for uv in uvs.iter_mut() {
+            if placement.rotated {
+                *uv = [size[1] as f32 - uv[1], uv[0]];
+            }
+            *uv = [uv[0] + placement.x as f32, uv[1] + placement.y as f32];
+        }
+    }
+
+    packing
+}
+
+enum Bin {
+    Skyline(Vec<[u32; 3]>), // segments as x, y, width, ordered by x and covering the page
+    MaxRects(Vec<[u32; 4]>), // free rectangles as x, y, width, height
+    Full,
+}
+
+impl Bin {
+    fn insert(
+        &mut self,
+        size: [u32; 2],
+        rotate: bool,
+        page_size: [u32; 2],
+    ) -> Option<(u32, u32, bool)> {
+        let orientations: &[bool] = if rotate && size[0] != size[1] {
+            &[false, true]
+        } else {
+            &[false]
+        };
+        let oriented = |rotated: bool| {
+            if rotated {
+                (size[1], size[0])
+            } else {
+                (size[0], size[1])
+            }
+        };
+
+        match self {
+            Bin::Skyline(segments) => {
+                // Lowest top edge, then leftmost
+                let mut best: Option<((u32, u32), usize, u32, bool)> = None;
+                for &rotated in orientations {
+                    let (w, h) = oriented(rotated);
+                    for i in 0..segments.len() {
+                        if let Some(y) = skyline_fit(segments, i, w, h, page_size) {
+                            let score = (y + h, segments[i][0]);
+                            if best.map(|b| score < b.0).unwrap_or(true) {
+                                best = Some((score, i, y, rotated));
+                            }
+                        }
+                    }
+                }
+
+                let (_, i, y, rotated) = best?;
+                let (w, h) = oriented(rotated);
+                let x = segments[i][0];
+                skyline_add(segments, x, y + h, w);
+                Some((x, y, rotated))
+            }
+            Bin::MaxRects(free) => {
+                // Best short side fit, on an unbounded page the short side is the width so
+                // that falls back to the lowest top edge instead
+                let mut best: Option<((u32, u32), usize, bool)> = None;
+                for &rotated in orientations {
+                    let (w, h) = oriented(rotated);
+                    for (i, f) in free.iter().enumerate() {
+                        if w > f[2] || h > f[3] {
+                            continue;
+                        }
+                        let score = if page_size[1] == u32::MAX {
+                            (f[1] + h, f[0])
+                        } else {
+                            let (dw, dh) = (f[2] - w, f[3] - h);
+                            (dw.min(dh), dw.max(dh))
+                        };
+                        if best.map(|b| score < b.0).unwrap_or(true) {
+                            best = Some((score, i, rotated));
+                        }
+                    }
+                }
+
+                let (_, i, rotated) = best?;
+                let (w, h) = oriented(rotated);
+                let (x, y) = (free[i][0], free[i][1]);
+                max_rects_split(free, [x, y, w, h]);
+                Some((x, y, rotated))
+            }
+            Bin::Full => None,
+        }
+    }
+}
+
+// Height at which a rect of width `w` would rest when its left edge is at segment `i`
+fn skyline_fit(
+    segments: &[[u32; 3]],
+    i: usize,
+    w: u32,
+    h: u32,
+    page_size: [u32; 2],
+) -> Option<u32> {
+    let x = segments[i][0];
+    if w > page_size[0] - x {
+        return None;
+    }
+
+    let mut y = 0;
+    let mut covered = 0;
+    for segment in &segments[i..] {
+        if covered >= w {
+            break;
+        }
+        y = y.max(segment[1]);
+        covered += segment[2];
+    }
+    if covered < w || h > page_size[1] - y {
+        return None;
+    }
+    Some(y)
+}
+
+fn skyline_add(segments: &mut Vec<[u32; 3]>, x: u32, top: u32, w: u32) {
+    let mut result = Vec::with_capacity(segments.len() + 2);
+    for &[sx, sy, sw] in segments.iter() {
+        let end = sx + sw;
+        if end <= x || sx >= x + w {
+            result.push([sx, sy, sw]);
+            continue;
+        }
+        if sx < x {
+            result.push([sx, sy, x - sx]);
+        }
+        if end > x + w {
+            result.push([x + w, sy, end - (x + w)]);
+        }
+    }
+    result.push([x, top, w]);
+    result.sort_by_key(|s| s[0]);
+
+    segments.clear();
+    for s in result {
+        match segments.last_mut() {
+            Some(last) if last[1] == s[1] => last[2] += s[2],
+            _ => segments.push(s),
+        }
+    }
+}
+
+fn max_rects_split(free: &mut Vec<[u32; 4]>, used: [u32; 4]) {
+    let [ux, uy, uw, uh] = used;
+    let mut split = vec![];
+    free.retain(|&[fx, fy, fw, fh]| {
+        if ux >= fx + fw || ux + uw <= fx || uy >= fy + fh || uy + uh <= fy {
+            return true;
+        }
+        if ux > fx {
+            split.push([fx, fy, ux - fx, fh]);
+        }
+        if ux + uw < fx + fw {
+            split.push([ux + uw, fy, fx + fw - (ux + uw), fh]);
+        }
+        if uy > fy {
+            split.push([fx, fy, fw, uy - fy]);
+        }
+        if uy + uh < fy + fh {
+            split.push([fx, uy + uh, fw, fy + fh - (uy + uh)]);
+        }
+        false
+    });
+
+    // Only the new rects can be contained in others or contain old ones
+    let contains = |a: &[u32; 4], b: &[u32; 4]| {
+        b[0] >= a[0] && b[1] >= a[1] && b[0] + b[2] <= a[0] + a[2] && b[1] + b[3] <= a[1] + a[3]
+    };
+    let mut kept: Vec<[u32; 4]> = vec![];
+    for (i, r) in split.iter().enumerate() {
+        let redundant = free.iter().any(|f| contains(f, r))
+            || split
+                .iter()
+                .enumerate()
+                .any(|(j, s)| j != i && contains(s, r) && (s != r || j < i));
+        if !redundant {
+            kept.push(*r);
+        }
+    }
+    free.retain(|f| !kept.iter().any(|r| contains(r, f)));
+    free.extend(kept);
+}
+
+fn bounds(uvs: &[[f32; 2]]) -> ([f32; 2], [f32; 2]) {
+    let mut min = [f32::MAX; 2];
+    let mut max = [f32::MIN; 2];
+    for uv in uvs {
+        for c in 0..2 {
+            min[c] = min[c].min(uv[c]);
+            max[c] = max[c].max(uv[c]);
+        }
+    }
+    if uvs.is_empty() {
+        ([0.0; 2], [0.0; 2])
+    } else {
+        (min, max)
+    }
+}
+
+// Angle of the edge of the convex hull that the minimum area bounding rectangle is aligned with
+fn min_area_rect_angle(uvs: &[[f32; 2]]) -> f32 {
+    let hull = convex_hull(uvs);
+    if hull.len() < 3 {
+        return 0.0;
+    }
+
+    let mut best = (f32::MAX, 0.0);
+    for i in 0..hull.len() {
+        let (a, b) = (hull[i], hull[(i + 1) % hull.len()]);
+        let angle = (b[1] - a[1]).atan2(b[0] - a[0]);
+        let (sin, cos) = angle.sin_cos();
+        let mut min = [f32::MAX; 2];
+        let mut max = [f32::MIN; 2];
+        for p in &hull {
+            let r = [p[0] * cos + p[1] * sin, p[1] * cos - p[0] * sin];
+            for c in 0..2 {
+                min[c] = min[c].min(r[c]);
+                max[c] = max[c].max(r[c]);
+            }
+        }
+        let area = (max[0] - min[0]) * (max[1] - min[1]);
+        if area < best.0 {
+            best = (area, angle);
+        }
+    }
+    best.1
+}
+
+// Monotone chain, counter clockwise without collinear points
+fn convex_hull(points: &[[f32; 2]]) -> Vec<[f32; 2]> {
+    let mut points = points.to_vec();
+    points.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+
+    let cross = |o: [f32; 2], a: [f32; 2], b: [f32; 2]| {
+        (a[0] - o[0]) * (b[1] - o[1]) - (a[1] - o[1]) * (b[0] - o[0])
+    };
+    let mut hull: Vec<[f32; 2]> = vec![];
+    for pass in 0..2 {
+        let start = hull.len();
+        let ordered: Box<dyn Iterator<Item = &[f32; 2]>> = if pass == 0 {
+            Box::new(points.iter())
+        } else {
+            Box::new(points.iter().rev())
+        };
+        for &p in ordered {
+            while hull.len() >= start + 2
+                && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(p);
+        }
+        hull.pop();
+    }
+    hull
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sizes that don't pack trivially, from a fixed linear congruential sequence
+    fn sizes(count: usize) -> Vec<[u32; 2]> {
+        let mut state = 12345u32;
+        let mut next = |max: u32| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            1 + (state >> 16) % max
+        };
+        (0..count).map(|_| [next(40), next(24)]).collect()
+    }
+
+    fn rects(sizes: &[[u32; 2]], packing: &Packing) -> Vec<(u32, [u32; 4])> {
+        sizes
+            .iter()
+            .zip(&packing.placements)
+            .map(|(s, p)| {
+                let (w, h) = if p.rotated {
+                    (s[1], s[0])
+                } else {
+                    (s[0], s[1])
+                };
+                (p.atlas_index, [p.x, p.y, w, h])
+            })
+            .collect()
+    }
+
+    fn assert_disjoint(rects: &[(u32, [u32; 4])]) {
+        for (i, (page, a)) in rects.iter().enumerate() {
+            for (other, b) in &rects[i + 1..] {
+                let apart = a[0] + a[2] <= b[0]
+                    || b[0] + b[2] <= a[0]
+                    || a[1] + a[3] <= b[1]
+                    || b[1] + b[3] <= a[1];
+                assert!(page != other || apart, "{:?} overlaps {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn rects_dont_overlap() {
+        let sizes = sizes(200);
+        for &strategy in &[PackStrategy::Skyline, PackStrategy::MaxRects] {
+            for &rotate in &[false, true] {
+                let packing = pack_rects(&sizes, strategy, rotate, [128, 128], 0.0);
+                let rects = rects(&sizes, &packing);
+                assert_disjoint(&rects);
+                for (page, r) in &rects {
+                    assert!(*page < packing.atlas_count);
+                    assert!(r[0] + r[2] <= 128 && r[1] + r[3] <= 128);
+                }
+                assert!(packing.atlas_count > 1);
+            }
+        }
+    }
+
+    #[test]
+    fn unbounded_pages_follow_the_aspect_ratio() {
+        let sizes = sizes(300);
+        for &strategy in &[PackStrategy::Skyline, PackStrategy::MaxRects] {
+            let packing = pack_rects(&sizes, strategy, false, [0, 0], 2.0);
+            assert_disjoint(&rects(&sizes, &packing));
+            assert_eq!(packing.atlas_count, 1);
+            let ratio = packing.width as f32 / packing.height as f32;
+            assert!(ratio > 1.5 && ratio < 3.0, "{}", ratio);
+        }
+    }
+
+    #[test]
+    fn oversized_rects_get_their_own_page() {
+        let sizes = [[8, 8], [100, 10], [8, 8]];
+        let packing = pack_rects(&sizes, PackStrategy::Skyline, false, [64, 64], 0.0);
+        assert_eq!(packing.atlas_count, 2);
+        assert_eq!(
+            packing.placements[0].atlas_index,
+            packing.placements[2].atlas_index
+        );
+        assert_ne!(
+            packing.placements[1].atlas_index,
+            packing.placements[0].atlas_index
+        );
+    }
+
+    #[test]
+    fn charts_are_padded_and_aligned() {
+        let options = PackOptions {
+            strategy: PackStrategy::MaxRects,
+            rotation: PackRotation::Arbitrary,
+            padding: 1,
+            padding_mip_level: 1,
+            block_align: true,
+            block_size: [6, 6],
+            ..Default::default()
+        };
+        // Thin diagonal charts, which the arbitrary rotation turns upright
+        let mut charts: Vec<Vec<[f32; 2]>> = (0..20)
+            .map(|i| {
+                let l = 10.0 + i as f32;
+                vec![[0.0, 0.0], [l, l], [l - 2.0, l + 2.0], [-2.0, 2.0]]
+            })
+            .collect();
+        let packing = pack_charts(&mut charts, &options);
+        assert_eq!(options.alignment(), [6, 6]);
+        assert_eq!(packing.width % 6, 0);
+        assert_eq!(packing.height % 6, 0);
+
+        let mut boxes = vec![];
+        for (uvs, placement) in charts.iter().zip(&packing.placements) {
+            assert_eq!(placement.x % 6, 0);
+            assert_eq!(placement.y % 6, 0);
+            let (min, max) = bounds(uvs);
+            // Two texels of padding at mip 0, and upright after the rotation
+            assert!(min[0] >= placement.x as f32 + 2.0 - 1e-3);
+            assert!(min[1] >= placement.y as f32 + 2.0 - 1e-3);
+            assert!((max[0] - min[0]).min(max[1] - min[1]) < 3.0);
+            boxes.push((
+                placement.atlas_index,
+                [
+                    min[0] as u32 - 2,
+                    min[1] as u32 - 2,
+                    (max[0] - min[0]).ceil() as u32 + 4,
+                    (max[1] - min[1]).ceil() as u32 + 4,
+                ],
+            ));
+        }
+        assert_disjoint(&boxes);
+    }
+
+    #[test]
+    fn hull_and_minimum_rectangle() {
+        let square = [
+            [0.0, 0.0],
+            [1.0, 0.0],
+            [1.0, 1.0],
+            [0.0, 1.0],
+            [0.5, 0.5],
+            [0.5, 0.0],
+        ];
+        let hull = convex_hull(&square);
+        assert_eq!(hull.len(), 4);
+
+        let (sin, cos) = 0.3f32.sin_cos();
+        let rect: Vec<[f32; 2]> = [[0.0, 0.0], [4.0, 0.0], [4.0, 1.0], [0.0, 1.0]]
+            .iter()
+            .map(|p| [p[0] * cos - p[1] * sin, p[0] * sin + p[1] * cos])
+            .collect();
+        let angle = min_area_rect_angle(&rect);
+        let turns = (angle - 0.3) / std::f32::consts::FRAC_PI_2;
+        assert!((turns - turns.round()).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,348 @@
+// As-rigid-as-possible refinement (Liu et al. 2008, local/global)
+//
+// Every iteration fits a rotation per triangle and then solves a cotangent Laplacian for new
+// texcoords. The global step is only taken as far as it can be without flipping a triangle,
+// so a chart that starts without flips never ends up with any.
+
+use super::sparse::{conjugate_gradient, SparseMatrix};
+use crate::math::*;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ArapOptions {
+    pub iterations: u32,
+    pub threshold: f32, // stop once the relative energy decrease falls below this
+}
+
+impl Default for ArapOptions {
+    fn default() -> ArapOptions {
+        ArapOptions {
+            iterations: 10,
+            threshold: 1e-4,
+        }
+    }
+}
+
+struct Triangle {
+    vertices: [usize; 3],
+    local: [[f64; 2]; 3],
+    cot: [f64; 3], // cotangent of the angle opposite to edge (i, i + 1)
+}
+
+// Refines the texcoords of a chart in place and returns the number of iterations that ran.
+// Charts that contain flipped (clockwise) triangles are left untouched.
+pub fn refine(
+    positions: &[[f32; 3]],
+    indices: &[u32],
+    texcoords: &mut [[f32; 2]],
+    options: &ArapOptions,
+) -> u32 {
+    let mut triangles = vec![];
+    for t in indices.chunks_exact(3) {
+        let vertices = [t[0] as usize, t[1] as usize, t[2] as usize];
+        let (p0, p1, p2) = (
+            positions[vertices[0]],
+            positions[vertices[1]],
+            positions[vertices[2]],
+        );
+        let e1 = sub(p1, p0);
+        let e2 = sub(p2, p0);
+        let n = cross(e1, e2);
+        if length(n) <= f32::EPSILON * dot(e1, e1).max(dot(e2, e2)) {
+            continue;
+        }
+
+        let x_axis = normalize(e1);
+        let y_axis = normalize(cross(n, x_axis));
+        let local = [
+            [0.0, 0.0],
+            [length(e1) as f64, 0.0],
+            [dot(e2, x_axis) as f64, dot(e2, y_axis) as f64],
+        ];
+
+        let mut cot = [0.0; 3];
+        for (i, cot) in cot.iter_mut().enumerate() {
+            let o = local[(i + 2) % 3];
+            let a = [local[i][0] - o[0], local[i][1] - o[1]];
+            let b = [local[(i + 1) % 3][0] - o[0], local[(i + 1) % 3][1] - o[1]];
+            let sin = a[0] * b[1] - a[1] * b[0];
+            *cot = (a[0] * b[0] + a[1] * b[1]) / sin;
+        }
+
+        triangles.push(Triangle {
+            vertices,
+            local,
+            cot,
+        });
+    }
+
+    let uv_area = |uv: &[[f64; 2]], t: &Triangle| {
+        let [a, b, c] = t.vertices;
+        (uv[b][0] - uv[a][0]) * (uv[c][1] - uv[a][1])
+            - (uv[b][1] - uv[a][1]) * (uv[c][0] - uv[a][0])
+    };
+
+    let mut uv: Vec<[f64; 2]> = texcoords
+        .iter()
+        .map(|uv| [uv[0] as f64, uv[1] as f64])
+        .collect();
+
+    if triangles.is_empty() || triangles.iter().any(|t| uv_area(&uv, t) <= 0.0) {
+        return 0;
+    }
+
+    // Vertex 0 of the first triangle stays put to remove the translational freedom
+    let pinned = triangles[0].vertices[0];
+    let mut unknown = vec![usize::MAX; positions.len()];
+    let mut unknown_count = 0;
+    for t in &triangles {
+        for &v in &t.vertices {
+            if v != pinned && unknown[v] == usize::MAX {
+                unknown[v] = unknown_count;
+                unknown_count += 1;
+            }
+        }
+    }
+
+    let mut triplets = vec![];
+    for t in &triangles {
+        for e in 0..3 {
+            let (i, j) = (t.vertices[e], t.vertices[(e + 1) % 3]);
+            let w = t.cot[e];
+            for &(a, b) in &[(i, j), (j, i)] {
+                if unknown[a] == usize::MAX {
+                    continue;
+                }
+                triplets.push((unknown[a], unknown[a], w));
+                if unknown[b] != usize::MAX {
+                    triplets.push((unknown[a], unknown[b], -w));
+                }
+            }
+        }
+    }
+    let laplacian = SparseMatrix::from_triplets(unknown_count, unknown_count, triplets);
+
+    let energy = |uv: &[[f64; 2]], rotations: &[[f64; 2]]| -> f64 {
+        triangles
+            .iter()
+            .zip(rotations)
+            .map(|(t, r)| {
+                (0..3)
+                    .map(|e| {
+                        let (i, j) = (t.vertices[e], t.vertices[(e + 1) % 3]);
+                        let x = [
+                            t.local[e][0] - t.local[(e + 1) % 3][0],
+                            t.local[e][1] - t.local[(e + 1) % 3][1],
+                        ];
+                        let rx = [r[0] * x[0] - r[1] * x[1], r[1] * x[0] + r[0] * x[1]];
+                        let du = [uv[i][0] - uv[j][0] - rx[0], uv[i][1] - uv[j][1] - rx[1]];
+                        t.cot[e] * (du[0] * du[0] + du[1] * du[1])
+                    })
+                    .sum::<f64>()
+            })
+            .sum()
+    };
+
+    let mut previous_energy = f64::MAX;
+    let mut iteration = 0;
+    while iteration < options.iterations {
+        iteration += 1;
+
+        // Local step, best fitting rotation (stored as cos, sin) per triangle
+        let rotations: Vec<[f64; 2]> = triangles
+            .iter()
+            .map(|t| {
+                let mut s = [[0.0; 2]; 2];
+                for e in 0..3 {
+                    let (i, j) = (t.vertices[e], t.vertices[(e + 1) % 3]);
+                    let u = [uv[i][0] - uv[j][0], uv[i][1] - uv[j][1]];
+                    let x = [
+                        t.local[e][0] - t.local[(e + 1) % 3][0],
+                        t.local[e][1] - t.local[(e + 1) % 3][1],
+                    ];
+                    for r in 0..2 {
+                        for c in 0..2 {
+                            s[r][c] += t.cot[e] * u[r] * x[c];
+                        }
+                    }
+                }
+                let angle = (s[1][0] - s[0][1]).atan2(s[0][0] + s[1][1]);
+                [angle.cos(), angle.sin()]
+            })
+            .collect();
+
+        let current_energy = energy(&uv, &rotations);
+        if previous_energy - current_energy < options.threshold as f64 * previous_energy {
+            break;
+        }
+        previous_energy = current_energy;
+
+        // Global step, solved separately for u and v
+        let mut rhs = [vec![0.0; unknown_count], vec![0.0; unknown_count]];
+        for (t, r) in triangles.iter().zip(&rotations) {
+            for e in 0..3 {
+                let (i, j) = (t.vertices[e], t.vertices[(e + 1) % 3]);
+                let x = [
+                    t.local[e][0] - t.local[(e + 1) % 3][0],
+                    t.local[e][1] - t.local[(e + 1) % 3][1],
+                ];
+                let rx = [r[0] * x[0] - r[1] * x[1], r[1] * x[0] + r[0] * x[1]];
+                let w = t.cot[e];
+                for c in 0..2 {
+                    if unknown[i] != usize::MAX {
+                        rhs[c][unknown[i]] += w * rx[c];
+                        if unknown[j] == usize::MAX {
+                            rhs[c][unknown[i]] += w * uv[j][c];
+                        }
+                    }
+                    if unknown[j] != usize::MAX {
+                        rhs[c][unknown[j]] -= w * rx[c];
+                        if unknown[i] == usize::MAX {
+                            rhs[c][unknown[j]] += w * uv[i][c];
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut target = uv.clone();
+        for (c, rhs) in rhs.iter().enumerate() {
+            let mut x: Vec<f64> = vec![0.0; unknown_count];
+            for (v, &u) in unknown.iter().enumerate() {
+                if u != usize::MAX {
+                    x[u] = uv[v][c];
+                }
+            }
+            conjugate_gradient(&laplacian, rhs, &mut x, 4 * unknown_count + 100, 1e-10);
+            for (v, &u) in unknown.iter().enumerate() {
+                if u != usize::MAX {
+                    target[v][c] = x[u];
+                }
+            }
+        }
+
+        if target
+            .iter()
+            .any(|uv| !uv[0].is_finite() || !uv[1].is_finite())
+        {
+            break;
+        }
+
+        let step = max_flip_free_step(&triangles, &uv, &target);
+        if step <= 0.0 {
+            break;
+        }
+        for (uv, target) in uv.iter_mut().zip(&target) {
+            uv[0] += (target[0] - uv[0]) * step;
+            uv[1] += (target[1] - uv[1]) * step;
+        }
+    }
+
+    for (texcoord, uv) in texcoords.iter_mut().zip(&uv) {
+        *texcoord = [uv[0] as f32, uv[1] as f32];
+    }
+
+    iteration
+}
+
+// Largest step in [0, 1] towards `target` for which no triangle reaches zero area. The signed
+// area along the path is a quadratic in the step size, stay clear of its first positive root.
+fn max_flip_free_step(triangles: &[Triangle], uv: &[[f64; 2]], target: &[[f64; 2]]) -> f64 {
+    let mut step = 1.0f64;
+
+    for t in triangles {
+        let [a, b, c] = t.vertices;
+        let d = |v: usize| [target[v][0] - uv[v][0], target[v][1] - uv[v][1]];
+        let e = |p: usize, q: usize| [uv[q][0] - uv[p][0], uv[q][1] - uv[p][1]];
+        let de = |p: usize, q: usize| {
+            let (dp, dq) = (d(p), d(q));
+            [dq[0] - dp[0], dq[1] - dp[1]]
+        };
+        let cross = |x: [f64; 2], y: [f64; 2]| x[0] * y[1] - x[1] * y[0];
+
+        let (e1, e2, d1, d2) = (e(a, b), e(a, c), de(a, b), de(a, c));
+        let c0 = cross(e1, e2);
+        let c1 = cross(e1, d2) + cross(d1, e2);
+        let c2 = cross(d1, d2);
+
+        let root = if c2.abs() < 1e-30 {
+            if c1 < 0.0 {
+                -c0 / c1
+            } else {
+                f64::MAX
+            }
+        } else {
+            let discriminant = c1 * c1 - 4.0 * c2 * c0;
+            if discriminant < 0.0 {
+                f64::MAX
+            } else {
+                let sqrt = discriminant.sqrt();
+                let r0 = (-c1 - sqrt) / (2.0 * c2);
+                let r1 = (-c1 + sqrt) / (2.0 * c2);
+                let (r0, r1) = if r0 < r1 { (r0, r1) } else { (r1, r0) };
+                if r0 > 0.0 {
+                    r0
+                } else if r1 > 0.0 {
+                    r1
+                } else {
+                    f64::MAX
+                }
+            }
+        };
+
+        if root <= 1.0 {
+            step = step.min(root * 0.8);
+        }
+    }
+
+    step
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::dome;
+    use super::super::{flipped_faces, overlapping_faces};
+    use super::*;
+
+    // Squared difference between uv and surface edge lengths
+    fn stretch(positions: &[[f32; 3]], indices: &[u32], texcoords: &[[f32; 2]]) -> f32 {
+        indices
+            .chunks_exact(3)
+            .flat_map(|t| (0..3).map(move |i| (t[i] as usize, t[(i + 1) % 3] as usize)))
+            .map(|(a, b)| {
+                let uv = sub2(texcoords[a], texcoords[b]);
+                let uv = (uv[0] * uv[0] + uv[1] * uv[1]).sqrt();
+                (uv - length(sub(positions[a], positions[b]))).powi(2)
+            })
+            .sum()
+    }
+
+    #[test]
+    fn stretched_charts_relax() {
+        let (positions, indices) = dome(6, 0.2);
+        let mut texcoords: Vec<[f32; 2]> = positions.iter().map(|p| [p[0] * 2.0, p[1]]).collect();
+        let before = stretch(&positions, &indices, &texcoords);
+
+        let options = ArapOptions {
+            iterations: 20,
+            ..Default::default()
+        };
+        assert!(refine(&positions, &indices, &mut texcoords, &options) > 0);
+        let after = stretch(&positions, &indices, &texcoords);
+        assert!(after < before * 0.1, "{} {}", before, after);
+        assert!(flipped_faces(&indices, &texcoords).is_empty());
+        assert!(overlapping_faces(&indices, &texcoords).is_empty());
+    }
+
+    #[test]
+    fn flipped_charts_are_left_alone() {
+        let (positions, indices) = dome(4, 0.2);
+        let mut texcoords: Vec<[f32; 2]> = positions.iter().map(|p| [p[0], p[1]]).collect();
+        texcoords[6] = [0.2, -0.4];
+        let before = texcoords.clone();
+        assert_eq!(
+            refine(&positions, &indices, &mut texcoords, &Default::default()),
+            0
+        );
+        assert_eq!(texcoords, before);
+    }
+}
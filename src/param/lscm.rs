@@ -0,0 +1,184 @@
+// Least squares conformal maps (Lévy et al. 2002)
+//
+// Two vertices that are far apart are pinned to their positions in the chart's best fit plane,
+// the remaining vertices are solved for with the sparse least squares solver.
+
+use super::sparse::{least_squares, SparseMatrix};
+use super::{normalize_area, planar_projection};
+use crate::math::*;
+
+pub fn parameterize(positions: &[[f32; 3]], indices: &[u32], texcoords: &mut [[f32; 2]]) {
+    planar_projection(positions, indices, texcoords);
+    if indices.len() < 6 {
+        return;
+    }
+
+    let pins = match find_pins(indices, texcoords) {
+        Some(pins) => pins,
+        None => return,
+    };
+
+    // Unknowns are interleaved (u, v) pairs of the free vertices
+    let mut free = vec![usize::MAX; positions.len()];
+    let mut free_count = 0;
+    for &i in indices {
+        let i = i as usize;
+        if free[i] == usize::MAX && i != pins[0] && i != pins[1] {
+            free[i] = free_count;
+            free_count += 1;
+        }
+    }
+
+    let mut triplets = vec![];
+    let mut rhs = vec![];
+    for t in indices.chunks_exact(3) {
+        let (p0, p1, p2) = (
+            positions[t[0] as usize],
+            positions[t[1] as usize],
+            positions[t[2] as usize],
+        );
+        let e1 = sub(p1, p0);
+        let e2 = sub(p2, p0);
+        let n = cross(e1, e2);
+        let double_area = length(n);
+        if double_area <= f32::EPSILON * dot(e1, e1).max(dot(e2, e2)) {
+            continue;
+        }
+
+        // Triangle in its own plane, p0 at the origin and p1 on the x axis
+        let x_axis = normalize(e1);
+        let y_axis = normalize(cross(n, x_axis));
+        let local = [
+            [0.0, 0.0],
+            [length(e1) as f64, 0.0],
+            [dot(e2, x_axis) as f64, dot(e2, y_axis) as f64],
+        ];
+
+        let weight = 1.0 / (double_area as f64).sqrt();
+        let row = rhs.len();
+        rhs.push(0.0);
+        rhs.push(0.0);
+
+        for j in 0..3 {
+            let a = local[(j + 1) % 3];
+            let b = local[(j + 2) % 3];
+            let w = [(b[0] - a[0]) * weight, (b[1] - a[1]) * weight];
+
+            let vertex = t[j] as usize;
+            // Real part: wr * u - wi * v, imaginary part: wi * u + wr * v
+            let coefficients = [[w[0], -w[1]], [w[1], w[0]]];
+            if free[vertex] == usize::MAX {
+                let uv = texcoords[vertex];
+                for (r, c) in coefficients.iter().enumerate() {
+                    rhs[row + r] -= c[0] * uv[0] as f64 + c[1] * uv[1] as f64;
+                }
+            } else {
+                for (r, c) in coefficients.iter().enumerate() {
+                    triplets.push((row + r, free[vertex] * 2, c[0]));
+                    triplets.push((row + r, free[vertex] * 2 + 1, c[1]));
+                }
+            }
+        }
+    }
+
+    if rhs.is_empty() || free_count == 0 {
+        return;
+    }
+
+    let matrix = SparseMatrix::from_triplets(rhs.len(), free_count * 2, triplets);
+    let mut x = vec![0.0; free_count * 2];
+    for (vertex, &f) in free.iter().enumerate() {
+        if f != usize::MAX {
+            x[f * 2] = texcoords[vertex][0] as f64;
+            x[f * 2 + 1] = texcoords[vertex][1] as f64;
+        }
+    }
+
+    least_squares(&matrix, &rhs, &mut x, 20 * free_count + 100, 1e-10);
+
+    if x.iter().any(|v| !v.is_finite()) {
+        return;
+    }
+    for (vertex, &f) in free.iter().enumerate() {
+        if f != usize::MAX {
+            texcoords[vertex] = [x[f * 2] as f32, x[f * 2 + 1] as f32];
+        }
+    }
+
+    normalize_area(positions, indices, texcoords);
+}
+
+// The extremes along the longest axis of the planar projection
+fn find_pins(indices: &[u32], texcoords: &[[f32; 2]]) -> Option<[usize; 2]> {
+    let mut min = [f32::MAX; 2];
+    let mut max = [f32::MIN; 2];
+    for &i in indices {
+        let uv = texcoords[i as usize];
+        for c in 0..2 {
+            min[c] = min[c].min(uv[c]);
+            max[c] = max[c].max(uv[c]);
+        }
+    }
+
+    let axis = if max[0] - min[0] >= max[1] - min[1] {
+        0
+    } else {
+        1
+    };
+    let by_axis = |a: &&u32, b: &&u32| {
+        texcoords[**a as usize][axis].total_cmp(&texcoords[**b as usize][axis])
+    };
+    let first = *indices.iter().min_by(by_axis)? as usize;
+    let second = *indices.iter().max_by(by_axis)? as usize;
+
+    if first == second {
+        None
+    } else {
+        Some([first, second])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::dome;
+    use super::super::{flipped_faces, overlapping_faces};
+    use super::*;
+
+    #[test]
+    fn dome_is_flip_free() {
+        let (positions, indices) = dome(8, 0.3);
+        let mut texcoords = vec![[0.0; 2]; positions.len()];
+        parameterize(&positions, &indices, &mut texcoords);
+        assert!(flipped_faces(&indices, &texcoords).is_empty());
+        assert!(overlapping_faces(&indices, &texcoords).is_empty());
+
+        let area = |t: &[u32]| {
+            let uv = |c: usize| texcoords[t[c] as usize];
+            (signed_area2(uv(0), uv(1), uv(2)) * 0.5).abs()
+        };
+        let uv_area: f32 = indices.chunks_exact(3).map(area).sum();
+        let surface_area: f32 = indices
+            .chunks_exact(3)
+            .map(|t| {
+                let p = |c: usize| positions[t[c] as usize];
+                triangle_area(p(0), p(1), p(2))
+            })
+            .sum();
+        assert!((uv_area / surface_area - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn planar_charts_keep_their_shape() {
+        let (positions, indices) = dome(4, 0.0);
+        let mut texcoords = vec![[0.0; 2]; positions.len()];
+        parameterize(&positions, &indices, &mut texcoords);
+
+        // Conformal with the same area is a rigid motion for a flat chart
+        for &(a, b) in &[(0, 24), (4, 20), (7, 13), (0, 1)] {
+            let d = length(sub(positions[a], positions[b]));
+            let uv = sub2(texcoords[a], texcoords[b]);
+            let uv = (uv[0] * uv[0] + uv[1] * uv[1]).sqrt();
+            assert!((uv - d).abs() < 1e-4, "{} {}", uv, d);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+// Chart parameterization implemented in Rust, these run from xatlas' `ParameterizeFunc` hook
+// and work on the vertices and triangles of a single chart.
+
+pub mod arap;
+pub mod lscm;
+pub(crate) mod sparse;
+pub mod tutte;
+
+use crate::math::*;
+
+// Orthogonal projection onto the plane perpendicular to the area weighted chart normal
+pub(crate) fn planar_projection(
+    positions: &[[f32; 3]],
+    indices: &[u32],
+    texcoords: &mut [[f32; 2]],
+) {
+    let mut normal = [0.0; 3];
+    for t in indices.chunks_exact(3) {
+        let (p0, p1, p2) = (
+            positions[t[0] as usize],
+            positions[t[1] as usize],
+            positions[t[2] as usize],
+        );
+        normal = add(normal, cross(sub(p1, p0), sub(p2, p0)));
+    }
+
+    if length(normal) <= 0.0 {
+        normal = [0.0, 0.0, 1.0];
+    }
+
+    let (tangent, bitangent) = tangent_frame(normal);
+    for (uv, &p) in texcoords.iter_mut().zip(positions) {
+        *uv = [dot(p, tangent), dot(p, bitangent)];
+    }
+}
+
+// Rescale texcoords so the chart covers the same area in uv space as it does on the surface
+pub(crate) fn normalize_area(positions: &[[f32; 3]], indices: &[u32], texcoords: &mut [[f32; 2]]) {
+    let mut surface_area = 0.0f64;
+    let mut uv_area = 0.0f64;
+    for t in indices.chunks_exact(3) {
+        let (a, b, c) = (t[0] as usize, t[1] as usize, t[2] as usize);
+        surface_area += triangle_area(positions[a], positions[b], positions[c]) as f64;
+        uv_area += (signed_area2(texcoords[a], texcoords[b], texcoords[c]) * 0.5).abs() as f64;
+    }
+
+    if uv_area <= 0.0 || surface_area <= 0.0 {
+        return;
+    }
+
+    let s = (surface_area / uv_area).sqrt() as f32;
+    for uv in texcoords.iter_mut() {
+        *uv = [uv[0] * s, uv[1] * s];
+    }
+}
+
+// Faces whose uv winding is opposite to that of the majority of the chart
+pub fn flipped_faces(indices: &[u32], texcoords: &[[f32; 2]]) -> Vec<u32> {
+    let areas: Vec<f32> = indices
+        .chunks_exact(3)
+        .map(|t| {
+            signed_area2(
+                texcoords[t[0] as usize],
+                texcoords[t[1] as usize],
+                texcoords[t[2] as usize],
+            )
+        })
+        .collect();
+
+    let positive = areas.iter().filter(|&&a| a > 0.0).count();
+    let negative = areas.iter().filter(|&&a| a < 0.0).count();
+    let flipped = if positive >= negative { -1.0 } else { 1.0 };
+
+    (0..areas.len() as u32)
+        .filter(|&f| areas[f as usize] * flipped > 0.0)
+        .collect()
+}
+
+// Faces whose interior overlaps that of another face of the chart in uv space.
+// Triangles are bucketed in a grid so only nearby pairs get tested.
+pub fn overlapping_faces(indices: &[u32], texcoords: &[[f32; 2]]) -> Vec<u32> {
+    let triangles: Vec<[[f32; 2]; 3]> = indices
+        .chunks_exact(3)
+        .map(|t| {
+            [
+                texcoords[t[0] as usize],
+                texcoords[t[1] as usize],
+                texcoords[t[2] as usize],
+            ]
+        })
+        .collect();
+    if triangles.len() < 2 {
+        return vec![];
+    }
+
+    let mut min = [f32::MAX; 2];
+    let mut max = [f32::MIN; 2];
+    for p in triangles.iter().flatten() {
+        for c in 0..2 {
+            min[c] = min[c].min(p[c]);
+            max[c] = max[c].max(p[c]);
+        }
+    }
+
+    let extent = (max[0] - min[0]).max(max[1] - min[1]);
+    if extent <= 0.0 || !extent.is_finite() {
+        return vec![];
+    }
+    let epsilon = extent * 1e-5;
+    let cells = ((triangles.len() as f32).sqrt().ceil() as usize).max(1);
+    let cell_size = extent / cells as f32;
+    let cell_range = |t: &[[f32; 2]; 3]| {
+        let mut range = [[usize::MAX, 0]; 2];
+        for p in t {
+            for c in 0..2 {
+                let cell = (((p[c] - min[c]) / cell_size) as usize).min(cells - 1);
+                range[c][0] = range[c][0].min(cell);
+                range[c][1] = range[c][1].max(cell);
+            }
+        }
+        range
+    };
+
+    let ranges: Vec<[[usize; 2]; 2]> = triangles.iter().map(cell_range).collect();
+    let mut grid = vec![vec![]; cells * cells];
+    for (i, r) in ranges.iter().enumerate() {
+        for y in r[1][0]..=r[1][1] {
+            for x in r[0][0]..=r[0][1] {
+                grid[y * cells + x].push(i);
+            }
+        }
+    }
+
+    let mut overlapping = vec![false; triangles.len()];
+    for (cell, members) in grid.iter().enumerate() {
+        let (x, y) = (cell % cells, cell / cells);
+        for (n, &a) in members.iter().enumerate() {
+            for &b in &members[n + 1..] {
+                // Only test a pair in the first cell both of them are in
+                let (ra, rb) = (ranges[a], ranges[b]);
+                if x != ra[0][0].max(rb[0][0]) || y != ra[1][0].max(rb[1][0]) {
+                    continue;
+                }
+                if triangles_overlap(&triangles[a], &triangles[b], epsilon) {
+                    overlapping[a] = true;
+                    overlapping[b] = true;
+                }
+            }
+        }
+    }
+
+    (0..triangles.len() as u32)
+        .filter(|&f| overlapping[f as usize])
+        .collect()
+}
+
+// Separating axis test, triangles that merely touch along an edge or corner don't overlap
+fn triangles_overlap(a: &[[f32; 2]; 3], b: &[[f32; 2]; 3], epsilon: f32) -> bool {
+    for t in &[a, b] {
+        for i in 0..3 {
+            let edge = sub2(t[(i + 1) % 3], t[i]);
+            let axis = [-edge[1], edge[0]];
+            let len = (axis[0] * axis[0] + axis[1] * axis[1]).sqrt();
+            if len <= 0.0 {
+                continue;
+            }
+
+            let project = |t: &[[f32; 2]; 3]| {
+                let d: Vec<f32> = t
+                    .iter()
+                    .map(|p| (p[0] * axis[0] + p[1] * axis[1]) / len)
+                    .collect();
+                (d[0].min(d[1]).min(d[2]), d[0].max(d[1]).max(d[2]))
+            };
+            let (a_min, a_max) = project(a);
+            let (b_min, b_max) = project(b);
+            if a_max.min(b_max) - a_min.max(b_min) <= epsilon {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // `n` x `n` quads over [-1, 1]^2, lifted into a dome of the given height
+    pub(crate) fn dome(n: u32, height: f32) -> (Vec<[f32; 3]>, Vec<u32>) {
+        let mut positions = vec![];
+        for i in 0..=n {
+            for j in 0..=n {
+                let x = i as f32 / n as f32 * 2.0 - 1.0;
+                let y = j as f32 / n as f32 * 2.0 - 1.0;
+                positions.push([x, y, height * (2.0 - x * x - y * y)]);
+            }
+        }
+        let mut indices = vec![];
+        for i in 0..n {
+            for j in 0..n {
+                let v = |i: u32, j: u32| i * (n + 1) + j;
+                let quad = [v(i, j), v(i + 1, j), v(i + 1, j + 1), v(i, j + 1)];
+                indices.extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+            }
+        }
+        (positions, indices)
+    }
+
+    #[test]
+    fn flipped_and_overlapping() {
+        let (positions, indices) = dome(4, 0.0);
+        let mut texcoords: Vec<[f32; 2]> = positions.iter().map(|p| [p[0], p[1]]).collect();
+        assert!(flipped_faces(&indices, &texcoords).is_empty());
+        assert!(overlapping_faces(&indices, &texcoords).is_empty());
+
+        // Pulling an inner vertex past its neighbour folds the faces around it over
+        texcoords[6] = [0.2, -0.4];
+        assert!(!flipped_faces(&indices, &texcoords).is_empty());
+        assert!(!overlapping_faces(&indices, &texcoords).is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+// Minimal sparse matrix support for the parameterizers, no attempt is made at being a general purpose solver
+
+#[derive(Debug, Default, Clone)]
+pub struct SparseMatrix {
+    pub rows: usize,
+    pub cols: usize,
+    row_start: Vec<usize>,
+    col_index: Vec<usize>,
+    values: Vec<f64>,
+}
+
+impl SparseMatrix {
+    // Duplicate entries are summed
+    pub fn from_triplets(rows: usize, cols: usize, mut triplets: Vec<(usize, usize, f64)>) -> Self {
+        triplets.sort_by_key(|&(r, c, _)| (r, c));
+
+        let mut matrix = SparseMatrix {
+            rows,
+            cols,
+            row_start: vec![0; rows + 1],
+            col_index: Vec::with_capacity(triplets.len()),
+            values: Vec::with_capacity(triplets.len()),
+        };
+
+        let mut last = None;
+        for (r, c, v) in triplets {
+            if last == Some((r, c)) {
+                *matrix.values.last_mut().unwrap() += v;
+                continue;
+            }
+            last = Some((r, c));
+            matrix.col_index.push(c);
+            matrix.values.push(v);
+            matrix.row_start[r + 1] = matrix.values.len();
+        }
+
+        for r in 0..rows {
+            matrix.row_start[r + 1] = matrix.row_start[r + 1].max(matrix.row_start[r]);
+        }
+
+        matrix
+    }
+
+    pub fn mul(&self, x: &[f64], out: &mut [f64]) {
+        for (r, out) in out.iter_mut().enumerate().take(self.rows) {
+            let range = self.row_start[r]..self.row_start[r + 1];
+            *out = self.col_index[range.clone()]
+                .iter()
+                .zip(&self.values[range])
+                .map(|(&c, &v)| v * x[c])
+                .sum();
+        }
+    }
+
+    pub fn mul_transposed(&self, x: &[f64], out: &mut [f64]) {
+        for v in out.iter_mut() {
+            *v = 0.0;
+        }
+        for (r, &x) in x.iter().enumerate().take(self.rows) {
+            for i in self.row_start[r]..self.row_start[r + 1] {
+                out[self.col_index[i]] += self.values[i] * x;
+            }
+        }
+    }
+
+    pub fn diagonal(&self) -> Vec<f64> {
+        let mut diagonal = vec![0.0; self.rows.min(self.cols)];
+        for (r, d) in diagonal.iter_mut().enumerate() {
+            for i in self.row_start[r]..self.row_start[r + 1] {
+                if self.col_index[i] == r {
+                    *d += self.values[i];
+                }
+            }
+        }
+        diagonal
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+// Jacobi preconditioned conjugate gradient for symmetric positive definite `a`, starts from the contents of `x`.
+// Returns the number of iterations used.
+pub fn conjugate_gradient(
+    a: &SparseMatrix,
+    b: &[f64],
+    x: &mut [f64],
+    max_iterations: usize,
+    tolerance: f64,
+) -> usize {
+    let n = b.len();
+    let inv_diagonal: Vec<f64> = a
+        .diagonal()
+        .iter()
+        .map(|&d| if d.abs() > 1e-300 { 1.0 / d } else { 1.0 })
+        .collect();
+
+    let mut r = vec![0.0; n];
+    a.mul(x, &mut r);
+    for i in 0..n {
+        r[i] = b[i] - r[i];
+    }
+
+    let threshold = tolerance * dot(b, b).sqrt().max(1e-300);
+    let mut z: Vec<f64> = r.iter().zip(&inv_diagonal).map(|(r, d)| r * d).collect();
+    let mut p = z.clone();
+    let mut rz = dot(&r, &z);
+    let mut q = vec![0.0; n];
+
+    for iteration in 0..max_iterations {
+        if dot(&r, &r).sqrt() <= threshold {
+            return iteration;
+        }
+
+        a.mul(&p, &mut q);
+        let pq = dot(&p, &q);
+        if pq.abs() < 1e-300 {
+            return iteration;
+        }
+
+        let alpha = rz / pq;
+        for i in 0..n {
+            x[i] += alpha * p[i];
+            r[i] -= alpha * q[i];
+            z[i] = r[i] * inv_diagonal[i];
+        }
+
+        let rz_new = dot(&r, &z);
+        let beta = rz_new / rz;
+        rz = rz_new;
+        for i in 0..n {
+            p[i] = z[i] + beta * p[i];
+        }
+    }
+
+    max_iterations
+}
+
+// Minimizes |a x - b| with conjugate gradients on the normal equations (CGLS), starts from the contents of `x`.
+// Returns the number of iterations used.
+pub fn least_squares(
+    a: &SparseMatrix,
+    b: &[f64],
+    x: &mut [f64],
+    max_iterations: usize,
+    tolerance: f64,
+) -> usize {
+    let mut r = vec![0.0; a.rows];
+    a.mul(x, &mut r);
+    for i in 0..a.rows {
+        r[i] = b[i] - r[i];
+    }
+
+    let mut s = vec![0.0; a.cols];
+    a.mul_transposed(&r, &mut s);
+    let mut p = s.clone();
+    let mut gamma = dot(&s, &s);
+    let threshold = tolerance * tolerance * gamma.max(1e-300);
+    let mut q = vec![0.0; a.rows];
+
+    for iteration in 0..max_iterations {
+        if gamma <= threshold {
+            return iteration;
+        }
+
+        a.mul(&p, &mut q);
+        let qq = dot(&q, &q);
+        if qq < 1e-300 {
+            return iteration;
+        }
+
+        let alpha = gamma / qq;
+        for i in 0..a.cols {
+            x[i] += alpha * p[i];
+        }
+        for i in 0..a.rows {
+            r[i] -= alpha * q[i];
+        }
+
+        a.mul_transposed(&r, &mut s);
+        let gamma_new = dot(&s, &s);
+        let beta = gamma_new / gamma;
+        gamma = gamma_new;
+        for i in 0..a.cols {
+            p[i] = s[i] + beta * p[i];
+        }
+    }
+
+    max_iterations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn residual(a: &SparseMatrix, x: &[f64], b: &[f64]) -> f64 {
+        let mut ax = vec![0.0; a.rows];
+        a.mul(x, &mut ax);
+        ax.iter()
+            .zip(b)
+            .map(|(ax, b)| (ax - b).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    #[test]
+    fn conjugate_gradient_solves_a_laplacian() {
+        // 1D Laplacian with a diagonal shift, symmetric positive definite
+        let n = 50;
+        let mut triplets = vec![];
+        for i in 0..n {
+            triplets.push((i, i, 2.5));
+            if i > 0 {
+                triplets.push((i, i - 1, -1.0));
+                triplets.push((i - 1, i, -1.0));
+            }
+        }
+        let a = SparseMatrix::from_triplets(n, n, triplets);
+        let b: Vec<f64> = (0..n).map(|i| (i as f64).sin()).collect();
+        let mut x = vec![0.0; n];
+
+        let iterations = conjugate_gradient(&a, &b, &mut x, 200, 1e-12);
+        assert!(iterations > 0 && iterations < 200);
+        assert!(residual(&a, &x, &b) < 1e-9);
+
+        // Starting from the solution needs no iterations
+        assert_eq!(conjugate_gradient(&a, &b, &mut x, 200, 1e-6), 0);
+    }
+
+    #[test]
+    fn least_squares_fits_a_line() {
+        // Duplicate triplets are summed: rows of [x, 1] for y = 2x + 1 with symmetric noise
+        let xs = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+        let mut triplets = vec![];
+        let mut b = vec![];
+        for (row, &x) in xs.iter().enumerate() {
+            triplets.push((row, 0, x * 0.5));
+            triplets.push((row, 0, x * 0.5));
+            triplets.push((row, 1, 1.0));
+            b.push(2.0 * x + 1.0 + if row % 2 == 0 { 0.1 } else { -0.1 });
+        }
+        let a = SparseMatrix::from_triplets(xs.len(), 2, triplets);
+        let mut solution = vec![0.0; 2];
+        least_squares(&a, &b, &mut solution, 100, 1e-12);
+
+        // Normal equations of the same system solved directly
+        let (n, sx, sxx) = (6.0, 15.0, 55.0);
+        let sy: f64 = b.iter().sum();
+        let sxy: f64 = xs.iter().zip(&b).map(|(x, y)| x * y).sum();
+        let slope = (n * sxy - sx * sy) / (n * sxx - sx * sx);
+        let intercept = (sy - slope * sx) / n;
+        assert!((solution[0] - slope).abs() < 1e-9, "{:?}", solution);
+        assert!((solution[1] - intercept).abs() < 1e-9, "{:?}", solution);
+    }
+}
@@ -0,0 +1,166 @@
+// Tutte embedding (barycentric mapping with uniform weights)
+//
+// The longest boundary loop is fixed to a circle and every other vertex is placed at the average of
+// its neighbours. For a chart with disk topology this is guaranteed to be free of flips and overlaps,
+// which makes it the fallback when another parameterization produced an invalid chart. Holes are
+// closed with a virtual vertex so charts with more than one boundary still map to a disk.
+
+use std::collections::HashSet;
+
+use super::normalize_area;
+use super::sparse::{conjugate_gradient, SparseMatrix};
+use crate::math::*;
+use crate::preprocess::{self, edge_faces};
+
+// Returns false, leaving texcoords untouched, for charts without a boundary
+pub fn parameterize(positions: &[[f32; 3]], indices: &[u32], texcoords: &mut [[f32; 2]]) -> bool {
+    let mut loops = boundary_loops(indices);
+    if loops.is_empty() {
+        return false;
+    }
+
+    let loop_length = |l: &Vec<u32>| -> f32 {
+        (0..l.len())
+            .map(|i| {
+                length(sub(
+                    positions[l[i] as usize],
+                    positions[l[(i + 1) % l.len()] as usize],
+                ))
+            })
+            .sum()
+    };
+    loops.sort_by(|a, b| loop_length(b).total_cmp(&loop_length(a)));
+
+    let vertex_count = positions.len();
+    let mut neighbours: Vec<HashSet<usize>> = vec![HashSet::new(); vertex_count + loops.len() - 1];
+    for t in indices.chunks_exact(3) {
+        for i in 0..3 {
+            let (a, b) = (t[i] as usize, t[(i + 1) % 3] as usize);
+            if a != b {
+                neighbours[a].insert(b);
+                neighbours[b].insert(a);
+            }
+        }
+    }
+    for (hole, l) in loops.iter().enumerate().skip(1) {
+        let center = vertex_count + hole - 1;
+        for &v in l {
+            neighbours[center].insert(v as usize);
+            neighbours[v as usize].insert(center);
+        }
+    }
+
+    // Outer boundary on the unit circle, spaced by edge length
+    let mut uv = vec![[0.0f64; 2]; neighbours.len()];
+    let mut fixed = vec![false; neighbours.len()];
+    let outer = &loops[0];
+    let total = loop_length(outer).max(f32::MIN_POSITIVE) as f64;
+    let mut travelled = 0.0f64;
+    for (i, &v) in outer.iter().enumerate() {
+        let angle = travelled / total * std::f64::consts::PI * 2.0;
+        uv[v as usize] = [angle.cos(), angle.sin()];
+        fixed[v as usize] = true;
+        let next = outer[(i + 1) % outer.len()] as usize;
+        travelled += length(sub(positions[v as usize], positions[next])) as f64;
+    }
+
+    let mut unknown = vec![usize::MAX; neighbours.len()];
+    let mut unknown_count = 0;
+    for (v, n) in neighbours.iter().enumerate() {
+        if !fixed[v] && !n.is_empty() {
+            unknown[v] = unknown_count;
+            unknown_count += 1;
+        }
+    }
+
+    if unknown_count > 0 {
+        let mut triplets = vec![];
+        let mut rhs = [vec![0.0; unknown_count], vec![0.0; unknown_count]];
+        for (v, n) in neighbours.iter().enumerate() {
+            if unknown[v] == usize::MAX {
+                continue;
+            }
+            triplets.push((unknown[v], unknown[v], n.len() as f64));
+            for &o in n {
+                if unknown[o] != usize::MAX {
+                    triplets.push((unknown[v], unknown[o], -1.0));
+                } else {
+                    rhs[0][unknown[v]] += uv[o][0];
+                    rhs[1][unknown[v]] += uv[o][1];
+                }
+            }
+        }
+
+        let laplacian = SparseMatrix::from_triplets(unknown_count, unknown_count, triplets);
+        for (c, rhs) in rhs.iter().enumerate() {
+            let mut x = vec![0.0; unknown_count];
+            conjugate_gradient(&laplacian, rhs, &mut x, 10 * unknown_count + 100, 1e-12);
+            for (v, &u) in unknown.iter().enumerate() {
+                if u != usize::MAX {
+                    uv[v][c] = x[u];
+                }
+            }
+        }
+    }
+
+    for (texcoord, uv) in texcoords.iter_mut().zip(&uv) {
+        *texcoord = [uv[0] as f32, uv[1] as f32];
+    }
+
+    // The boundary is walked along the triangle winding, which ends up counter clockwise
+    normalize_area(positions, indices, texcoords);
+    true
+}
+
+// Boundary loops of the chart long enough to enclose something
+fn boundary_loops(indices: &[u32]) -> Vec<Vec<u32>> {
+    preprocess::boundary_loops(&edge_faces(indices))
+        .into_iter()
+        .filter(|l| l.len() >= 3)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::dome;
+    use super::super::{flipped_faces, overlapping_faces};
+    use super::*;
+
+    #[test]
+    fn disks_and_holes_embed_without_flips() {
+        let (positions, indices) = dome(6, 0.5);
+        // The same dome with the four quads around its top cut out
+        let holed: Vec<u32> = indices
+            .chunks_exact(3)
+            .filter(|t| {
+                !t.iter()
+                    .all(|&v| [16, 17, 18, 23, 24, 25, 30, 31, 32].contains(&v))
+            })
+            .flatten()
+            .cloned()
+            .collect();
+        assert_eq!(holed.len(), indices.len() - 24);
+
+        for indices in &[indices, holed] {
+            let mut texcoords = vec![[0.0; 2]; positions.len()];
+            assert!(parameterize(&positions, indices, &mut texcoords));
+            assert!(flipped_faces(indices, &texcoords).is_empty());
+            assert!(overlapping_faces(indices, &texcoords).is_empty());
+        }
+    }
+
+    #[test]
+    fn closed_charts_are_rejected() {
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+        ];
+        let indices = [0, 2, 1, 0, 1, 3, 1, 2, 3, 2, 0, 3];
+        let mut texcoords = vec![[0.5; 2]; 4];
+        assert!(!parameterize(&positions, &indices, &mut texcoords));
+        assert_eq!(texcoords, [[0.5; 2]; 4]);
+        assert!(boundary_loops(&indices).is_empty());
+    }
+}
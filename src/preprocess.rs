@@ -0,0 +1,620 @@
+// Cleanup of input meshes before they are added to the atlas. Duplicated vertices and degenerate
+// or duplicated faces make xatlas produce many tiny charts, this welds and filters them and keeps
+// track of where every vertex and face came from.
+
+use crate::input::{bytes, triangulate, UnionFind};
+use crate::math::*;
+use crate::{AddMeshError, IndexFormat, MeshDecl};
+
+use std::collections::{HashMap, HashSet};
+
+// What happens to faces that are found by a cleanup step
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum FaceCleanup {
+    Keep,
+    Ignore, // kept, but marked in `face_ignore` so xatlas doesn't chart them
+    #[default]
+    Remove,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PreprocessOptions {
+    // Vertices closer than this are merged, 0 to only merge equal ones and negative to not weld
+    pub weld_epsilon: f32,
+    // Only weld vertices whose normals and uvs are within `weld_epsilon` as well
+    pub weld_attributes: bool,
+    // Faces with repeated vertices (after welding) or an area of at most `degenerate_area`
+    pub degenerate_faces: FaceCleanup,
+    pub degenerate_area: f32,
+    // Faces with the same vertices in the same winding as an earlier face
+    pub duplicate_faces: FaceCleanup,
+    // Give every fan of faces around a non-manifold vertex or edge its own copy of the vertex
+    pub split_non_manifold: bool,
+    // Flip faces so neighbours agree on the winding, keeping the winding of most faces
+    pub orient_faces: bool,
+}
+
+impl Default for PreprocessOptions {
+    fn default() -> Self {
+        PreprocessOptions {
+            weld_epsilon: 0.0,
+            weld_attributes: true,
+            degenerate_faces: FaceCleanup::default(),
+            degenerate_area: 0.0,
+            duplicate_faces: FaceCleanup::default(),
+            split_non_manifold: false,
+            orient_faces: false,
+        }
+    }
+}
+
+// A triangle mesh ready to be added with `mesh_decl`. Output vertices of the atlas refer to this
+// mesh through `Vertex::xref`, `original_vertex` maps them back to the input.
+#[derive(Debug, Default, Clone)]
+pub struct PreprocessedMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>, // empty when the input has none
+    pub uvs: Vec<[f32; 2]>,     // empty when the input has none
+    pub indices: Vec<u32>,
+    pub face_ignore: Vec<bool>, // per triangle, from the input and `FaceCleanup::Ignore`
+    pub original_vertex: Vec<u32>, // first input vertex that was welded into every vertex
+    pub vertex_map: Vec<u32>,   // input vertex -> vertex, u32::MAX when no face uses it anymore
+    pub original_face: Vec<u32>, // input face (polygon when using `face_vertex_counts`) of every triangle
+    pub welded_vertices: u32,    // input vertices that were merged into another one
+    pub degenerate_faces: u32,   // triangles found degenerate
+    pub duplicate_faces: u32,    // triangles found duplicated
+    pub manifold: ManifoldReport,
+}
+
+// Topology problems found after cleanup and before the repairs of `PreprocessOptions`, with what
+// those repairs changed. Vertices are input vertices (see `PreprocessedMesh::original_vertex`).
+#[derive(Debug, Default, Clone)]
+pub struct ManifoldReport {
+    pub non_manifold_edges: Vec<[u32; 2]>, // shared by more than two faces
+    pub non_manifold_vertices: Vec<u32>, // with more than one fan of faces, ends of non-manifold edges included
+    pub inconsistent_edges: Vec<[u32; 2]>, // shared by two faces with the same winding
+    pub boundary_loops: Vec<Vec<u32>>,   // in the winding of the faces along them
+    pub split_vertices: u32,             // copies added by `split_non_manifold`
+    pub flipped_faces: u32,              // by `orient_faces`
+}
+
+impl PreprocessedMesh {
+    // Per-face data of the input other than `face_ignore_data` has to be remapped through
+    // `original_face` by the caller.
+    pub fn mesh_decl(&self) -> MeshDecl<'_> {
+        MeshDecl {
+            vertex_count: self.positions.len() as u32,
+            vertex_position_data: bytes(&self.positions),
+            vertex_position_stride: 12,
+            vertex_normal_data: bytes(&self.normals),
+            vertex_normal_stride: 12,
+            vertex_uv_data: bytes(&self.uvs),
+            vertex_uv_stride: 8,
+            index_count: self.indices.len() as u32,
+            index_data: bytes(&self.indices),
+            index_format: IndexFormat::Uint32,
+            face_ignore_data: if self.face_ignore.iter().any(|&i| i) {
+                &self.face_ignore
+            } else {
+                &[]
+            },
+            ..Default::default()
+        }
+    }
+}
+
+pub fn preprocess(
+    decl: &MeshDecl,
+    options: &PreprocessOptions,
+) -> Result<PreprocessedMesh, AddMeshError> {
+    let positions = decl.decode_positions()?;
+    let normals = decl.decode_normals()?;
+    let uvs = decl.decode_uvs()?;
+    let face_indices = decl.decode_indices()?;
+    let face_sizes = decl.decode_face_sizes(&face_indices)?;
+    if !decl.face_ignore_data.is_empty() && decl.face_ignore_data.len() != face_sizes.len() {
+        return Err(AddMeshError::InvalidFaceData);
+    }
+
+    let weld = weld_vertices(&positions, &normals, &uvs, options);
+    let mut mesh = PreprocessedMesh {
+        welded_vertices: weld
+            .iter()
+            .enumerate()
+            .filter(|&(i, &w)| w != i as u32)
+            .count() as u32,
+        ..Default::default()
+    };
+
+    // Triangles on welded vertices, with their input face and whether they are ignored
+    let mut triangles: Vec<([u32; 3], u32, bool)> = vec![];
+    let mut first = 0;
+    for (face, &size) in face_sizes.iter().enumerate() {
+        let polygon: Vec<u32> = face_indices[first..first + size]
+            .iter()
+            .map(|&i| weld[i as usize])
+            .collect();
+        first += size;
+
+        let ignored = decl.face_ignore_data.get(face).cloned().unwrap_or(false);
+        for t in triangulate(&positions, &polygon).chunks_exact(3) {
+            triangles.push(([t[0], t[1], t[2]], face as u32, ignored));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut vertex_map = vec![u32::MAX; positions.len()];
+    for (t, face, mut ignored) in triangles {
+        let p = |c: usize| positions[t[c] as usize];
+        let degenerate = t[0] == t[1]
+            || t[1] == t[2]
+            || t[2] == t[0]
+            || triangle_area(p(0), p(1), p(2)) <= options.degenerate_area;
+
+        // Rotated so the lowest vertex comes first, which keeps the winding
+        let start = (0..3).min_by_key(|&c| t[c]).unwrap();
+        let key = [t[start], t[(start + 1) % 3], t[(start + 2) % 3]];
+        let duplicate = !degenerate && !seen.insert(key);
+
+        let cleanup = if degenerate {
+            mesh.degenerate_faces += 1;
+            options.degenerate_faces
+        } else if duplicate {
+            mesh.duplicate_faces += 1;
+            options.duplicate_faces
+        } else {
+            FaceCleanup::Keep
+        };
+        match cleanup {
+            FaceCleanup::Remove => continue,
+            FaceCleanup::Ignore => ignored = true,
+            FaceCleanup::Keep => {}
+        }
+
+        for &v in &t {
+            if vertex_map[v as usize] == u32::MAX {
+                vertex_map[v as usize] = mesh.positions.len() as u32;
+                mesh.positions.push(positions[v as usize]);
+                if !normals.is_empty() {
+                    mesh.normals.push(normals[v as usize]);
+                }
+                if !uvs.is_empty() {
+                    mesh.uvs.push(uvs[v as usize]);
+                }
+                mesh.original_vertex.push(v);
+            }
+            mesh.indices.push(vertex_map[v as usize]);
+        }
+        mesh.face_ignore.push(ignored);
+        mesh.original_face.push(face);
+    }
+
+    // Vertices that were welded map to wherever the vertex they were welded into went
+    mesh.vertex_map = weld.iter().map(|&w| vertex_map[w as usize]).collect();
+
+    mesh.manifold = check_manifold(&mesh);
+    if options.split_non_manifold {
+        mesh.manifold.split_vertices = split_non_manifold(&mut mesh);
+    }
+    if options.orient_faces {
+        mesh.manifold.flipped_faces = orient_faces(&mut mesh.indices);
+    }
+    Ok(mesh)
+}
+
+// Faces of every edge (lower vertex first), and whether they use it from the lower to the higher vertex
+pub(crate) fn edge_faces(indices: &[u32]) -> HashMap<(u32, u32), Vec<(u32, bool)>> {
+    let mut edges: HashMap<(u32, u32), Vec<(u32, bool)>> = HashMap::new();
+    for (face, t) in indices.chunks_exact(3).enumerate() {
+        for i in 0..3 {
+            let (a, b) = (t[i], t[(i + 1) % 3]);
+            let key = (a.min(b), a.max(b));
+            edges.entry(key).or_default().push((face as u32, a < b));
+        }
+    }
+    edges
+}
+
+// Loops of the edges with a single face, in the winding of the faces along them. Loops that
+// touch at a vertex come out separately, boundaries of inconsistently wound faces can't be
+// followed all the way around and come out as open chains.
+pub(crate) fn boundary_loops(edges: &HashMap<(u32, u32), Vec<(u32, bool)>>) -> Vec<Vec<u32>> {
+    let mut boundary: HashMap<u32, Vec<u32>> = HashMap::new(); // start -> ends of boundary edges
+    for (&(a, b), faces) in edges {
+        match faces.as_slice() {
+            [(_, true)] => boundary.entry(a).or_default().push(b),
+            [(_, false)] => boundary.entry(b).or_default().push(a),
+            _ => {}
+        }
+    }
+    for ends in boundary.values_mut() {
+        ends.sort_unstable_by(|a, b| b.cmp(a));
+    }
+
+    // Walk the boundary edges, starting from the lowest vertex so loops come out the same every time
+    let mut loops = vec![];
+    let mut starts: Vec<u32> = boundary.keys().cloned().collect();
+    starts.sort_unstable();
+    for start in starts {
+        while let Some(mut next) = boundary.get_mut(&start).and_then(|ends| ends.pop()) {
+            let mut walk = vec![start];
+            let mut on_walk: HashMap<u32, usize> = HashMap::new();
+            on_walk.insert(start, 0);
+            while next != start {
+                if let Some(&k) = on_walk.get(&next) {
+                    // Came back to a vertex where two loops touch, the part since is a loop of its own
+                    let touching = walk.split_off(k);
+                    for v in &touching[1..] {
+                        on_walk.remove(v);
+                    }
+                    loops.push(touching);
+                } else {
+                    on_walk.insert(next, walk.len());
+                }
+                walk.push(next);
+                next = match boundary.get_mut(&next).and_then(|ends| ends.pop()) {
+                    Some(next) => next,
+                    None => break, // open at a non-manifold vertex
+                };
+            }
+            loops.push(walk);
+        }
+    }
+    loops
+}
+
+// Corners (face * 3 + corner) grouped into the fans around their vertex. Fans are connected
+// across edges shared by two faces, of a non-manifold edge only the first face and the first
+// one after it with the opposite winding stay connected.
+fn corner_fans(indices: &[u32], edges: &HashMap<(u32, u32), Vec<(u32, bool)>>) -> Vec<u32> {
+    let mut fans = UnionFind::new(indices.len());
+    let corner = |face: u32, v: u32| {
+        let t = &indices[face as usize * 3..face as usize * 3 + 3];
+        face * 3 + t.iter().position(|&i| i == v).unwrap() as u32
+    };
+    for (&(a, b), faces) in edges {
+        let pair = match faces.len() {
+            1 => continue,
+            2 => faces[1],
+            _ => match faces[1..].iter().find(|f| f.1 != faces[0].1) {
+                Some(&face) => face,
+                None => continue,
+            },
+        };
+        for &v in &[a, b] {
+            fans.union(corner(faces[0].0, v), corner(pair.0, v));
+        }
+    }
+    (0..indices.len() as u32).map(|c| fans.find(c)).collect()
+}
+
+fn check_manifold(mesh: &PreprocessedMesh) -> ManifoldReport {
+    let original = |v: u32| mesh.original_vertex[v as usize];
+    let edges = edge_faces(&mesh.indices);
+    let mut report = ManifoldReport::default();
+
+    for (&(a, b), faces) in &edges {
+        match faces.len() {
+            1 => {}
+            2 if faces[0].1 == faces[1].1 => {
+                report.inconsistent_edges.push([original(a), original(b)])
+            }
+            2 => {}
+            _ => report.non_manifold_edges.push([original(a), original(b)]),
+        }
+    }
+
+    let fans = corner_fans(&mesh.indices, &edges);
+    let mut vertex_fans: Vec<Option<u32>> = vec![None; mesh.positions.len()];
+    let mut non_manifold = vec![false; mesh.positions.len()];
+    for (c, &v) in mesh.indices.iter().enumerate() {
+        match vertex_fans[v as usize] {
+            Some(fan) if fan != fans[c] => non_manifold[v as usize] = true,
+            Some(_) => {}
+            None => vertex_fans[v as usize] = Some(fans[c]),
+        }
+    }
+    report.non_manifold_vertices = (0..mesh.positions.len() as u32)
+        .filter(|&v| non_manifold[v as usize])
+        .map(original)
+        .collect();
+
+    report.boundary_loops = boundary_loops(&edges)
+        .into_iter()
+        .map(|boundary_loop| boundary_loop.into_iter().map(original).collect())
+        .collect();
+
+    report.non_manifold_edges.sort_unstable();
+    report.inconsistent_edges.sort_unstable();
+    report
+}
+
+// Every fan around a vertex after the first gets its own copy of the vertex, returns the number of copies
+fn split_non_manifold(mesh: &mut PreprocessedMesh) -> u32 {
+    let fans = corner_fans(&mesh.indices, &edge_faces(&mesh.indices));
+    let mut first_fan: Vec<Option<u32>> = vec![None; mesh.positions.len()];
+    let mut copies: HashMap<u32, u32> = HashMap::new(); // fan -> copy of its vertex
+    let mut split = 0;
+
+    for (c, &fan) in fans.iter().enumerate() {
+        let v = mesh.indices[c] as usize;
+        match first_fan[v] {
+            None => first_fan[v] = Some(fan),
+            Some(first) if first == fan => {}
+            Some(_) => {
+                let copy = *copies.entry(fan).or_insert_with(|| {
+                    split += 1;
+                    mesh.positions.push(mesh.positions[v]);
+                    if !mesh.normals.is_empty() {
+                        mesh.normals.push(mesh.normals[v]);
+                    }
+                    if !mesh.uvs.is_empty() {
+                        mesh.uvs.push(mesh.uvs[v]);
+                    }
+                    mesh.original_vertex.push(mesh.original_vertex[v]);
+                    mesh.positions.len() as u32 - 1
+                });
+                mesh.indices[c] = copy;
+            }
+        }
+    }
+    split
+}
+
+// Flips faces to agree with their neighbours across edges with two faces, per connected set of
+// faces the winding that most faces already have wins. Returns the number of flipped faces.
+fn orient_faces(indices: &mut [u32]) -> u32 {
+    let edges = edge_faces(indices);
+    let face_count = indices.len() / 3;
+    let mut visited = vec![false; face_count];
+    let mut flipped_total = 0;
+
+    for seed in 0..face_count {
+        if visited[seed] {
+            continue;
+        }
+        visited[seed] = true;
+        let mut component = vec![seed];
+        let mut flip = vec![false];
+        let mut queue = vec![0];
+
+        while let Some(i) = queue.pop() {
+            let face = component[i];
+            let t = [
+                indices[face * 3],
+                indices[face * 3 + 1],
+                indices[face * 3 + 2],
+            ];
+            for e in 0..3 {
+                let (a, b) = (t[e], t[(e + 1) % 3]);
+                let faces = &edges[&(a.min(b), a.max(b))];
+                if faces.len() != 2 {
+                    continue;
+                }
+                let (other, other_forward) = if faces[0].0 as usize == face {
+                    faces[1]
+                } else {
+                    faces[0]
+                };
+                let other = other as usize;
+                if visited[other] {
+                    continue;
+                }
+                visited[other] = true;
+
+                // Consistent neighbours use the edge in opposite directions
+                let forward = (a < b) != flip[i];
+                component.push(other);
+                flip.push(forward == other_forward);
+                queue.push(component.len() - 1);
+            }
+        }
+
+        let flips = flip.iter().filter(|&&f| f).count();
+        let keep = flips * 2 > component.len();
+        for (&face, &f) in component.iter().zip(&flip) {
+            if f != keep {
+                indices.swap(face * 3 + 1, face * 3 + 2);
+                flipped_total += 1;
+            }
+        }
+    }
+    flipped_total
+}
+
+// The vertex every vertex is welded into, the first one of its kind in input order
+fn weld_vertices(
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+    uvs: &[[f32; 2]],
+    options: &PreprocessOptions,
+) -> Vec<u32> {
+    let epsilon = options.weld_epsilon;
+    if epsilon < 0.0 {
+        return (0..positions.len() as u32).collect();
+    }
+
+    if epsilon == 0.0 {
+        return weld_exact(positions, normals, uvs, options.weld_attributes);
+    }
+
+    // Cells of at least twice the epsilon keep matches in neighbouring cells, and at most 2^20
+    // of them across the mesh keeps the indices of tiny epsilons far from saturating
+    let extent = positions
+        .iter()
+        .flat_map(|p| p.iter())
+        .fold(0.0f32, |extent, c| extent.max(c.abs()));
+    let cell_size = (epsilon * 2.0).max(extent / (1 << 20) as f32);
+    let cell = |p: [f32; 3]| {
+        [
+            (p[0] / cell_size).floor() as i64,
+            (p[1] / cell_size).floor() as i64,
+            (p[2] / cell_size).floor() as i64,
+        ]
+    };
+    let matches = |a: usize, b: usize| {
+        let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(a, b)| (a - b).abs() <= epsilon);
+        length(sub(positions[a], positions[b])) <= epsilon
+            && (!options.weld_attributes
+                || ((normals.is_empty() || close(&normals[a], &normals[b]))
+                    && (uvs.is_empty() || close(&uvs[a], &uvs[b]))))
+    };
+
+    let mut grid: HashMap<[i64; 3], Vec<u32>> = HashMap::new();
+    let mut weld = Vec::with_capacity(positions.len());
+    for (v, &p) in positions.iter().enumerate() {
+        let c = cell(p);
+        let mut found = None;
+        'search: for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let neighbour = [
+                        c[0].saturating_add(dx),
+                        c[1].saturating_add(dy),
+                        c[2].saturating_add(dz),
+                    ];
+                    let neighbours = match grid.get(&neighbour) {
+                        Some(neighbours) => neighbours,
+                        None => continue,
+                    };
+                    if let Some(&n) = neighbours.iter().find(|&&n| matches(v, n as usize)) {
+                        found = Some(n);
+                        break 'search;
+                    }
+                }
+            }
+        }
+
+        match found {
+            Some(n) => weld.push(n),
+            None => {
+                grid.entry(c).or_default().push(v as u32);
+                weld.push(v as u32);
+            }
+        }
+    }
+    weld
+}
+
+// Welds vertices with bitwise equal positions (and attributes), as `InputMesh::welded_vertices`
+// does. Adding 0 turns -0 into 0, which compare equal.
+fn weld_exact(
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+    uvs: &[[f32; 2]],
+    attributes: bool,
+) -> Vec<u32> {
+    let bits =
+        |values: &[f32]| -> Vec<u32> { values.iter().map(|v| (v + 0.0).to_bits()).collect() };
+    let mut lookup = HashMap::new();
+    (0..positions.len())
+        .map(|v| {
+            let mut key = bits(&positions[v]);
+            if attributes {
+                if !normals.is_empty() {
+                    key.extend(bits(&normals[v]));
+                }
+                if !uvs.is_empty() {
+                    key.extend(bits(&uvs[v]));
+                }
+            }
+            *lookup.entry(key).or_insert(v as u32)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::decl;
+
+    // Two triangles of a unit quad that don't share their vertices, the second one `offset` away
+    fn split_quad(offset: f32) -> Vec<[f32; 3]> {
+        vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [offset, 0.0, 0.0],
+            [1.0 + offset, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ]
+    }
+
+    #[test]
+    fn default_options_weld_equal_vertices() {
+        let indices = [0, 1, 2, 3, 4, 5];
+        let positions = split_quad(0.0);
+        let mesh = preprocess(&decl(&positions, &indices), &Default::default()).unwrap();
+        assert_eq!(mesh.welded_vertices, 2);
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.vertex_map, [0, 1, 2, 0, 2, 3]);
+
+        let positions = split_quad(1e-6);
+        let mesh = preprocess(&decl(&positions, &indices), &Default::default()).unwrap();
+        assert_eq!(mesh.welded_vertices, 0);
+    }
+
+    #[test]
+    fn weld_epsilon() {
+        let indices = [0, 1, 2, 3, 4, 5];
+        let positions = split_quad(1e-4);
+        for &(epsilon, welded) in &[(1e-3, 2), (1e-5, 0), (1e-30, 0), (-1.0, 0)] {
+            let options = PreprocessOptions {
+                weld_epsilon: epsilon,
+                ..Default::default()
+            };
+            let mesh = preprocess(&decl(&positions, &indices), &options).unwrap();
+            assert_eq!(mesh.welded_vertices, welded, "{}", epsilon);
+        }
+
+        // Cell indices of a tiny epsilon next to huge coordinates
+        let mut positions = split_quad(0.0);
+        positions[0] = [f32::MAX, -f32::MAX, 0.0];
+        let options = PreprocessOptions {
+            weld_epsilon: f32::MIN_POSITIVE,
+            ..Default::default()
+        };
+        let mesh = preprocess(&decl(&positions, &indices), &options).unwrap();
+        assert_eq!(mesh.welded_vertices, 1);
+    }
+
+    #[test]
+    fn non_manifold_edges_are_split_and_faces_oriented() {
+        // Three fins on the edge 0-1, and a quad whose second triangle is flipped
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.5, 1.0, 0.0],
+            [0.5, -1.0, 0.0],
+            [0.5, 0.0, 1.0],
+            [2.0, 0.0, 0.0],
+            [3.0, 0.0, 0.0],
+            [3.0, 1.0, 0.0],
+            [2.0, 1.0, 0.0],
+        ];
+        let indices = [0, 1, 2, 1, 0, 3, 0, 1, 4, 5, 6, 7, 5, 8, 7];
+        let options = PreprocessOptions {
+            split_non_manifold: true,
+            orient_faces: true,
+            ..Default::default()
+        };
+        let mesh = preprocess(&decl(&positions, &indices), &options).unwrap();
+
+        let report = &mesh.manifold;
+        assert_eq!(report.non_manifold_edges, [[0, 1]]);
+        assert_eq!(report.non_manifold_vertices, [0, 1]);
+        assert_eq!(report.inconsistent_edges, [[5, 7]]);
+        assert_eq!(report.split_vertices, 2);
+        assert_eq!(report.flipped_faces, 1);
+
+        // The third fin got its own copies of the edge, which still map back to the input
+        assert_eq!(mesh.positions.len(), 11);
+        assert_eq!(&mesh.original_vertex[9..], [0, 1]);
+        assert_eq!(&mesh.indices[6..9], [9, 10, 4]);
+        for faces in edge_faces(&mesh.indices).values() {
+            assert!(faces.len() <= 2);
+            assert!(faces.len() == 1 || faces[0].1 != faces[1].1);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use crate::math::*;
+use crate::ProjectionMode;
+
+// Faces are keyed by the projection they use; every connected set of faces with the same key is
+// flattened with that projection and handed to xatlas as a planar part, which xatlas turns into
+// a single chart and packs as usual. Faces that overlap within such a set are not detected.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Projection {
+    mode: ProjectionMode,
+    axis: Vec3,
+    center: Vec3,
+    radius: f32,
+}
+
+// Faces whose normal is within 45 degrees of the cylinder axis are capped
+const CYLINDER_CAP: f32 = std::f32::consts::FRAC_1_SQRT_2;
+// Faces above 60 degrees latitude are projected from the poles
+const SPHERE_CAP: f32 = std::f32::consts::FRAC_PI_3;
+
+// Cylindrical and spherical keys are `region * 2 + inward`
+const CAP_POSITIVE: u32 = 0;
+const CAP_NEGATIVE: u32 = 1;
+const SIDE: u32 = 2; // followed by the second half of the side
+
+impl Projection {
+    pub fn new(mode: ProjectionMode, positions: &[[f32; 3]], indices: &[u32]) -> Self {
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for &i in indices {
+            let p = positions[i as usize];
+            for c in 0..3 {
+                min[c] = min[c].min(p[c]);
+                max[c] = max[c].max(p[c]);
+            }
+        }
+        let center = if indices.is_empty() {
+            [0.0; 3]
+        } else {
+            scale(add(min, max), 0.5)
+        };
+
+        let axis = match mode {
+            // The dominant direction of the area weighted normals, for closed meshes where those
+            // cancel out the axis along which most of the area faces
+            ProjectionMode::Planar => {
+                let mut sum = [0.0; 3];
+                let mut abs = [0.0f32; 3];
+                let mut area = 0.0;
+                for t in indices.chunks_exact(3) {
+                    let n = face_normal(positions, t);
+                    sum = add(sum, n);
+                    for c in 0..3 {
+                        abs[c] += n[c].abs();
+                    }
+                    area += length(n);
+                }
+                if length(sum) > area * 1e-3 {
+                    normalize(sum)
+                } else {
+                    unit_axis(dominant_axis(abs))
+                }
+            }
+            // The longest extent of the bounding box
+            ProjectionMode::Cylindrical | ProjectionMode::Spherical => {
+                unit_axis(dominant_axis(sub(max, min)))
+            }
+            ProjectionMode::Box => [0.0, 0.0, 1.0],
+        };
+
+        let mut projection = Projection {
+            mode,
+            axis,
+            center,
+            radius: 1.0,
+        };
+
+        // Average distance to the axis (or center), u is measured along the surface at this radius
+        let mut vertices: Vec<u32> = indices.to_vec();
+        vertices.sort_unstable();
+        vertices.dedup();
+        let radius: f32 = vertices
+            .iter()
+            .map(|&v| length(projection.radial(positions[v as usize])))
+            .sum::<f32>()
+            / vertices.len().max(1) as f32;
+        if radius > 0.0 && radius.is_finite() {
+            projection.radius = radius;
+        }
+
+        projection
+    }
+
+    pub fn face_key(&self, positions: &[[f32; 3]], t: &[u32]) -> u32 {
+        let n = face_normal(positions, t);
+        let centroid = scale(
+            add(
+                add(positions[t[0] as usize], positions[t[1] as usize]),
+                positions[t[2] as usize],
+            ),
+            1.0 / 3.0,
+        );
+
+        match self.mode {
+            ProjectionMode::Planar => {
+                if dot(n, self.axis) >= 0.0 {
+                    CAP_POSITIVE
+                } else {
+                    CAP_NEGATIVE
+                }
+            }
+            ProjectionMode::Box => {
+                let axis = dominant_axis([n[0].abs(), n[1].abs(), n[2].abs()]);
+                axis as u32 * 2 + (n[axis] < 0.0) as u32
+            }
+            ProjectionMode::Cylindrical => {
+                let along = dot(normalize(n), self.axis);
+                if along > CYLINDER_CAP {
+                    CAP_POSITIVE * 2
+                } else if along < -CYLINDER_CAP {
+                    CAP_NEGATIVE * 2
+                } else {
+                    self.side_key(n, centroid)
+                }
+            }
+            ProjectionMode::Spherical => {
+                let latitude = self.latitude(centroid);
+                let inward = (dot(n, sub(centroid, self.center)) < 0.0) as u32;
+                if latitude > SPHERE_CAP {
+                    CAP_POSITIVE * 2 + inward
+                } else if latitude < -SPHERE_CAP {
+                    CAP_NEGATIVE * 2 + inward
+                } else {
+                    self.side_key(n, centroid)
+                }
+            }
+        }
+    }
+
+    // Position in the plane of the projection, in world units and counter clockwise for the faces
+    // that were given `key`
+    pub fn project(&self, key: u32, p: Vec3) -> [f32; 2] {
+        let planar = |direction: Vec3| {
+            let (t, b) = tangent_frame(direction);
+            [dot(p, t), dot(p, b)]
+        };
+        let negate = |v: Vec3| scale(v, -1.0);
+
+        match self.mode {
+            ProjectionMode::Planar => match key {
+                CAP_POSITIVE => planar(self.axis),
+                _ => planar(negate(self.axis)),
+            },
+            ProjectionMode::Box => {
+                let mut direction = unit_axis(key as usize / 2);
+                if key % 2 == 1 {
+                    direction = negate(direction);
+                }
+                planar(direction)
+            }
+            ProjectionMode::Cylindrical | ProjectionMode::Spherical => {
+                let (region, inward) = (key / 2, key % 2 == 1);
+                let mut uv = match region {
+                    CAP_POSITIVE => planar(self.axis),
+                    CAP_NEGATIVE => planar(negate(self.axis)),
+                    _ => {
+                        let u = self.longitude(p, region - SIDE) * self.radius;
+                        let v = match self.mode {
+                            ProjectionMode::Spherical => self.latitude(p) * self.radius,
+                            _ => dot(sub(p, self.center), self.axis),
+                        };
+                        [u, v]
+                    }
+                };
+                if inward {
+                    uv[0] = -uv[0];
+                }
+                uv
+            }
+        }
+    }
+
+    // Sides are split into two halves of longitude so no chart wraps around the axis
+    fn side_key(&self, n: Vec3, centroid: Vec3) -> u32 {
+        let radial = self.radial(centroid);
+        let (t, _) = tangent_frame(self.axis);
+        let half = (dot(radial, t) < 0.0) as u32;
+        let inward = (dot(n, radial) < 0.0) as u32;
+        (SIDE + half) * 2 + inward
+    }
+
+    // Offset from the axis, or from the center for spheres
+    fn radial(&self, p: Vec3) -> Vec3 {
+        let d = sub(p, self.center);
+        match self.mode {
+            ProjectionMode::Spherical => d,
+            _ => sub(d, scale(self.axis, dot(d, self.axis))),
+        }
+    }
+
+    // Angle around the axis relative to the middle of the given half, in [-pi, pi]
+    fn longitude(&self, p: Vec3, half: u32) -> f32 {
+        let (t, b) = tangent_frame(self.axis);
+        let d = sub(p, self.center);
+        let (x, y) = (dot(d, t), dot(d, b));
+        if half == 0 {
+            y.atan2(x)
+        } else {
+            (-y).atan2(-x)
+        }
+    }
+
+    fn latitude(&self, p: Vec3) -> f32 {
+        let d = normalize(sub(p, self.center));
+        dot(d, self.axis).clamp(-1.0, 1.0).asin()
+    }
+}
+
+fn face_normal(positions: &[[f32; 3]], t: &[u32]) -> Vec3 {
+    let (p0, p1, p2) = (
+        positions[t[0] as usize],
+        positions[t[1] as usize],
+        positions[t[2] as usize],
+    );
+    cross(sub(p1, p0), sub(p2, p0))
+}
+
+fn dominant_axis(v: Vec3) -> usize {
+    if v[0] >= v[1] && v[0] >= v[2] {
+        0
+    } else if v[1] >= v[2] {
+        1
+    } else {
+        2
+    }
+}
+
+fn unit_axis(axis: usize) -> Vec3 {
+    let mut v = [0.0; 3];
+    v[axis] = 1.0;
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::grid;
+    use std::collections::BTreeMap;
+
+    // Revolves the (radius, z) `profile` around z in `segments` steps starting at `angle`, faces
+    // wind counter clockwise seen from outside when the profile runs upwards
+    fn lathe(profile: &[(f32, f32)], segments: u32, angle: f32) -> (Vec<[f32; 3]>, Vec<u32>) {
+        let mut positions = vec![];
+        for &(r, z) in profile {
+            for s in 0..segments {
+                let a = angle + s as f32 / segments as f32 * std::f32::consts::PI * 2.0;
+                positions.push([r * a.cos(), r * a.sin(), z]);
+            }
+        }
+        let mut indices = vec![];
+        for i in 0..profile.len() as u32 - 1 {
+            for s in 0..segments {
+                let corner = |i: u32, s: u32| i * segments + s % segments;
+                let (a, b) = (corner(i, s), corner(i, s + 1));
+                let (c, d) = (corner(i + 1, s + 1), corner(i + 1, s));
+                for t in &[[a, b, c], [a, c, d]] {
+                    if length(face_normal(&positions, t)) > 1e-6 {
+                        indices.extend_from_slice(t);
+                    }
+                }
+            }
+        }
+        (positions, indices)
+    }
+
+    fn flipped(indices: &[u32]) -> Vec<u32> {
+        indices
+            .chunks_exact(3)
+            .flat_map(|t| vec![t[0], t[2], t[1]])
+            .collect()
+    }
+
+    // Number of faces per key, checking that every face projects counter clockwise
+    fn project_faces(
+        mode: ProjectionMode,
+        positions: &[[f32; 3]],
+        indices: &[u32],
+    ) -> BTreeMap<u32, usize> {
+        let projection = Projection::new(mode, positions, indices);
+        let mut keys = BTreeMap::new();
+        for t in indices.chunks_exact(3) {
+            let key = projection.face_key(positions, t);
+            let p = |c: usize| projection.project(key, positions[t[c] as usize]);
+            assert!(
+                signed_area2(p(0), p(1), p(2)) > 0.0,
+                "{:?} with key {}",
+                t,
+                key
+            );
+            *keys.entry(key).or_insert(0) += 1;
+        }
+        keys
+    }
+
+    #[test]
+    fn planar_height_field() {
+        let (positions, indices) = grid(4, |u, v| u * v * 0.5);
+        let keys = project_faces(ProjectionMode::Planar, &positions, &indices);
+        assert_eq!(
+            keys.into_iter().collect::<Vec<_>>(),
+            vec![(CAP_POSITIVE, 32)]
+        );
+
+        // The axis follows the normals, so a flipped height field is a single chart too
+        let keys = project_faces(ProjectionMode::Planar, &positions, &flipped(&indices));
+        assert_eq!(
+            keys.into_iter().collect::<Vec<_>>(),
+            vec![(CAP_POSITIVE, 32)]
+        );
+    }
+
+    #[test]
+    fn box_has_a_key_per_side() {
+        let r = std::f32::consts::SQRT_2;
+        let profile = [(0.0, -1.0), (r, -1.0), (r, 1.0), (0.0, 1.0)];
+        let (positions, indices) = lathe(&profile, 4, std::f32::consts::FRAC_PI_4);
+        let keys = project_faces(ProjectionMode::Box, &positions, &indices);
+        assert_eq!(
+            keys.keys().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4, 5]
+        );
+        assert!(keys.values().all(|&count| count >= 2));
+    }
+
+    #[test]
+    fn cylinder_side_is_split_in_halves() {
+        let profile = [(0.0, -2.0), (1.0, -2.0), (1.0, 2.0), (0.0, 2.0)];
+        let (positions, indices) = lathe(&profile, 16, 0.1);
+        let keys = project_faces(ProjectionMode::Cylindrical, &positions, &indices);
+        let side = |half: u32, inward: u32| (SIDE + half) * 2 + inward;
+        let expected = vec![
+            (CAP_POSITIVE * 2, 16),
+            (CAP_NEGATIVE * 2, 16),
+            (side(0, 0), 16),
+            (side(1, 0), 16),
+        ];
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), expected);
+
+        // Seen from inside the side halves are mirrored to stay counter clockwise
+        let keys = project_faces(ProjectionMode::Cylindrical, &positions, &flipped(&indices));
+        let expected = vec![
+            (CAP_POSITIVE * 2, 16),
+            (CAP_NEGATIVE * 2, 16),
+            (side(0, 1), 16),
+            (side(1, 1), 16),
+        ];
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn sphere_has_caps_and_two_halves() {
+        // Slightly taller than wide so the axis is z
+        let profile: Vec<(f32, f32)> = (0..=8)
+            .map(|i| {
+                let latitude = (i as f32 / 8.0 - 0.5) * std::f32::consts::PI;
+                (latitude.cos(), latitude.sin() * 1.1)
+            })
+            .collect();
+        let (positions, indices) = lathe(&profile, 16, 0.1);
+        let keys = project_faces(ProjectionMode::Spherical, &positions, &indices);
+        assert_eq!(keys.keys().copied().collect::<Vec<_>>(), vec![0, 2, 4, 6]);
+        assert_eq!(keys[&4], keys[&6]);
+    }
+}
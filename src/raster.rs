@@ -0,0 +1,110 @@
+// Triangle rasterization in texel space, texel (x, y) covers [x, x + 1] x [y, y + 1]
+
+use crate::math::*;
+
+// Calls `f` for every texel whose center lies inside the triangle (of either winding). With
+// `conservative` every texel the triangle overlaps is visited instead.
+pub(crate) fn rasterize_triangle<F>(t: [Vec2; 3], size: [u32; 2], conservative: bool, mut f: F)
+where
+    F: FnMut(u32, u32),
+{
+    let area = signed_area2(t[0], t[1], t[2]);
+    if !area.is_finite() || size[0] == 0 || size[1] == 0 {
+        return;
+    }
+
+    let mut min = [f32::MAX; 2];
+    let mut max = [f32::MIN; 2];
+    for p in &t {
+        for c in 0..2 {
+            min[c] = min[c].min(p[c]);
+            max[c] = max[c].max(p[c]);
+        }
+    }
+    let range = |c: usize| {
+        let first = min[c].floor().max(0.0) as u32;
+        let last = (max[c].floor().max(0.0) as u32).min(size[c] - 1);
+        first..=last
+    };
+
+    // Edge functions oriented so the inside is positive
+    let sign = if area < 0.0 { -1.0 } else { 1.0 };
+    let edges: Vec<(Vec2, Vec2)> = (0..3)
+        .map(|i| {
+            let (a, b) = (t[i], t[(i + 1) % 3]);
+            (a, [-(b[1] - a[1]) * sign, (b[0] - a[0]) * sign])
+        })
+        .collect();
+    let edge = |(a, n): &(Vec2, Vec2), p: Vec2| (p[0] - a[0]) * n[0] + (p[1] - a[1]) * n[1];
+
+    for y in range(1) {
+        for x in range(0) {
+            let inside = if conservative {
+                if area == 0.0 {
+                    true
+                } else {
+                    // The box overlaps unless it is entirely outside of one of the edges
+                    edges.iter().all(|e| {
+                        let corner = [
+                            x as f32 + if e.1[0] > 0.0 { 1.0 } else { 0.0 },
+                            y as f32 + if e.1[1] > 0.0 { 1.0 } else { 0.0 },
+                        ];
+                        edge(e, corner) >= 0.0
+                    })
+                }
+            } else {
+                let center = [x as f32 + 0.5, y as f32 + 0.5];
+                area != 0.0 && edges.iter().all(|e| edge(e, center) >= 0.0)
+            };
+
+            if inside {
+                f(x, y);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texels(t: [Vec2; 3], size: [u32; 2], conservative: bool) -> Vec<(u32, u32)> {
+        let mut texels = vec![];
+        rasterize_triangle(t, size, conservative, |x, y| texels.push((x, y)));
+        texels.sort_unstable();
+        texels
+    }
+
+    #[test]
+    fn texel_centers_and_coverage() {
+        let t = [[0.5, 0.5], [6.5, 0.5], [0.5, 6.5]];
+        let centers = texels(t, [8, 8], false);
+        // Centers on or below the hypotenuse x + y = 7
+        assert_eq!(centers.len(), 28);
+        assert!(centers.iter().all(|&(x, y)| x + y <= 6));
+
+        // Either winding gives the same texels
+        assert_eq!(texels([t[0], t[2], t[1]], [8, 8], false), centers);
+
+        // Conservative adds the texels the edges pass through
+        let covered = texels(t, [8, 8], true);
+        assert!(centers.iter().all(|texel| covered.contains(texel)));
+        assert!(
+            covered.contains(&(0, 0)) && covered.contains(&(6, 0)) && covered.contains(&(0, 6))
+        );
+        assert!(!covered.contains(&(4, 4)) && !covered.contains(&(7, 0)));
+    }
+
+    #[test]
+    fn triangles_are_clipped_to_the_image() {
+        let t = [[-4.0, -4.0], [12.0, -4.0], [-4.0, 12.0]];
+        let all = texels(t, [4, 4], false);
+        assert_eq!(all.len(), 16);
+        assert!(texels(t, [0, 4], true).is_empty());
+
+        // Zero area triangles have no texel centers but still cover the texels they touch
+        let line = [[0.5, 0.5], [2.5, 0.5], [1.5, 0.5]];
+        assert!(texels(line, [4, 4], false).is_empty());
+        assert!(!texels(line, [4, 4], true).is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+// Tangent frames for the atlas uvs, following MikkTSpace: per face tangents from the uv
+// derivatives, projected into the plane of every vertex normal and averaged weighted by the
+// corner angle. Output vertices are already split wherever the uvs are, so the average runs over
+// the faces sharing an output vertex, with faces of opposite uv orientation (mirrored uvs) kept
+// in separate groups as MikkTSpace does. A vertex used by both groups is split once more.
+
+use crate::math::*;
+use crate::{Tangents, Vertex};
+
+// One tangent per vertex, vertices of `vertices` used by faces of both uv orientations are
+// copied for the faces of the smaller group. `positions` and `normals` are indexed by
+// `Vertex::xref`.
+pub(crate) fn compute_tangents(
+    indices: &[u32],
+    vertices: &[Vertex],
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+) -> Tangents {
+    // Accumulated tangents and corner angles of the faces around every vertex, [0] for faces
+    // with counter clockwise uvs and [1] for mirrored ones
+    let mut groups = vec![[([0.0f32; 3], 0.0f32); 2]; vertices.len()];
+    let mut face_group = vec![None; indices.len() / 3];
+    let normal = |v: u32| normalize(normals[vertices[v as usize].xref as usize]);
+
+    for (f, t) in indices.chunks_exact(3).enumerate() {
+        let p = |c: usize| positions[vertices[t[c] as usize].xref as usize];
+        let uv = |c: usize| vertices[t[c] as usize].uv;
+        let (e1, e2) = (sub(p(1), p(0)), sub(p(2), p(0)));
+        let (d1, d2) = (sub2(uv(1), uv(0)), sub2(uv(2), uv(0)));
+        let area = cross2(d1, d2);
+        if area == 0.0 || !area.is_finite() {
+            continue;
+        }
+
+        // Direction of increasing u, and whether v runs along cross(normal, tangent) or against it
+        let (group, sign) = if area > 0.0 { (0, 1.0) } else { (1, -1.0) };
+        let tangent = normalize(scale(sub(scale(e1, d2[1]), scale(e2, d1[1])), sign));
+        face_group[f] = Some(group);
+
+        for (c, &v) in t.iter().enumerate() {
+            let n = normal(v);
+            let (a, b) = (sub(p((c + 1) % 3), p(c)), sub(p((c + 2) % 3), p(c)));
+            let project = |v: Vec3| normalize(sub(v, scale(n, dot(n, v))));
+            let angle = dot(project(a), project(b)).clamp(-1.0, 1.0).acos();
+            if !angle.is_finite() {
+                continue;
+            }
+
+            let (sum, weight) = &mut groups[v as usize][group];
+            *sum = add(*sum, scale(project(tangent), angle));
+            *weight += angle;
+        }
+    }
+
+    // Faces without usable uvs join the dominant group of the vertex, which keeps the vertex
+    let dominant = |v: u32| {
+        let around = &groups[v as usize];
+        (around[1].1 > around[0].1) as usize
+    };
+    let tangent = |v: u32, group: usize| {
+        let n = normal(v);
+        let sum = groups[v as usize][group].0;
+        let mut t = normalize(sub(sum, scale(n, dot(n, sum))));
+        if length(t) == 0.0 {
+            // No usable uvs around the vertex, any tangent will do
+            t = tangent_frame(n).0;
+        }
+        let w = if group == 1 { -1.0 } else { 1.0 };
+        [t[0], t[1], t[2], w]
+    };
+
+    let mut tangents = Tangents {
+        indices: Vec::with_capacity(indices.len()),
+        remap: (0..vertices.len() as u32).collect(),
+        tangents: (0..vertices.len() as u32)
+            .map(|v| tangent(v, dominant(v)))
+            .collect(),
+    };
+    let mut copies = vec![u32::MAX; vertices.len()];
+    for (corner, &v) in indices.iter().enumerate() {
+        let group = face_group[corner / 3].unwrap_or_else(|| dominant(v));
+        if group == dominant(v) {
+            tangents.indices.push(v);
+            continue;
+        }
+        if copies[v as usize] == u32::MAX {
+            copies[v as usize] = tangents.remap.len() as u32;
+            tangents.remap.push(v);
+            tangents.tangents.push(tangent(v, group));
+        }
+        tangents.indices.push(copies[v as usize]);
+    }
+    tangents
+}
+
+// Area weighted vertex normals, for inputs without normals
+pub(crate) fn vertex_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    let mut normals = vec![[0.0; 3]; positions.len()];
+    for t in indices.chunks_exact(3) {
+        let p = |c: usize| positions[t[c] as usize];
+        let n = cross(sub(p(1), p(0)), sub(p(2), p(0)));
+        for &v in t {
+            normals[v as usize] = add(normals[v as usize], n);
+        }
+    }
+    normals.iter().map(|&n| normalize(n)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(uv: [f32; 2], xref: u32) -> Vertex {
+        Vertex {
+            atlas_index: 0,
+            uv,
+            xref,
+        }
+    }
+
+    fn assert_tangent(actual: [f32; 4], expected: [f32; 4]) {
+        for c in 0..4 {
+            assert!((actual[c] - expected[c]).abs() < 1e-5, "{:?}", actual);
+        }
+    }
+
+    #[test]
+    fn mirrored_faces_keep_their_own_tangents() {
+        // Two triangles in the xy plane that share the edge x = 0, with u = |x| and v = y. The
+        // left one is mirrored, MikkTSpace gives its corners on the shared edge a tangent along -x.
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+        ];
+        let normals = [[0.0, 0.0, 1.0]; 4];
+        let vertices = [
+            vertex([0.0, 0.0], 0),
+            vertex([0.0, 1.0], 1),
+            vertex([1.0, 0.0], 2),
+            vertex([1.0, 0.0], 3),
+        ];
+        let indices = [2, 0, 1, 0, 3, 1];
+        let tangents = compute_tangents(&indices, &vertices, &positions, &normals);
+
+        // The shared vertices stay with the regular face and get copies for the mirrored one
+        assert_eq!(tangents.remap, vec![0, 1, 2, 3, 0, 1]);
+        assert_eq!(tangents.indices, vec![2, 4, 5, 0, 3, 1]);
+        assert_eq!(tangents.tangents.len(), tangents.remap.len());
+        for &v in &tangents.indices[..3] {
+            assert_tangent(tangents.tangents[v as usize], [-1.0, 0.0, 0.0, -1.0]);
+        }
+        for &v in &tangents.indices[3..] {
+            assert_tangent(tangents.tangents[v as usize], [1.0, 0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn tangents_follow_the_normal() {
+        // A quad in the xz plane facing +y with u along z and v along x
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0],
+            [1.0, 0.0, 0.0],
+        ];
+        let normals = vertex_normals(&positions, &[0, 1, 2, 0, 2, 3]);
+        let vertices = [
+            vertex([0.0, 0.0], 0),
+            vertex([1.0, 0.0], 1),
+            vertex([1.0, 1.0], 2),
+            vertex([0.0, 1.0], 3),
+        ];
+        let tangents = compute_tangents(&[0, 1, 2, 0, 2, 3], &vertices, &positions, &normals);
+
+        assert_eq!(normals[0], [0.0, 1.0, 0.0]);
+        assert_eq!(tangents.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(tangents.remap, vec![0, 1, 2, 3]);
+        for &corner in &tangents.tangents {
+            // cross(+y, +z) = +x, so v runs along the bitangent
+            assert_tangent(corner, [0.0, 0.0, 1.0, 1.0]);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+// Diagnostics for input meshes, to tell what is wrong with a mesh before it reaches the atlas
+
+use crate::input::{triangulate, UnionFind};
+use crate::math::*;
+use crate::preprocess::{boundary_loops, edge_faces};
+use crate::{AddMeshError, MeshDecl};
+
+#[derive(Debug, Default, Clone)]
+pub struct TopologyReport {
+    pub components: Vec<ComponentTopology>, // faces connected through shared vertices
+    pub boundary_edges: u32,                // used by a single face
+    pub non_manifold_edges: u32,            // used by more than two faces
+    pub degenerate_faces: u32,              // triangles with repeated vertices or no area
+    pub bounds: [[f32; 3]; 2],              // min and max of the vertices used by faces
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ComponentTopology {
+    // Triangles without repeated vertices, polygons count the triangles they are split into
+    pub faces: u32,
+    pub vertices: u32,
+    pub edges: u32,
+    pub boundary_loops: u32,
+    // From the Euler characteristic as an orientable surface, only meaningful for manifold components
+    pub genus: i32,
+}
+
+impl<'a> MeshDecl<'a> {
+    pub fn analyze(&self) -> Result<TopologyReport, AddMeshError> {
+        let positions = self.decode_positions()?;
+        let face_indices = self.decode_indices()?;
+        let face_sizes = self.decode_face_sizes(&face_indices)?;
+
+        let mut indices = vec![];
+        let mut first = 0;
+        for &size in &face_sizes {
+            indices.extend(triangulate(&positions, &face_indices[first..first + size]));
+            first += size;
+        }
+
+        let mut report = TopologyReport::default();
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for &v in &indices {
+            let p = positions[v as usize];
+            for c in 0..3 {
+                min[c] = min[c].min(p[c]);
+                max[c] = max[c].max(p[c]);
+            }
+        }
+        if !indices.is_empty() {
+            report.bounds = [min, max];
+        }
+
+        // Triangles with repeated vertices have no proper edges and are left out of the
+        // components, triangles with no area still connect their vertices
+        let mut proper = vec![];
+        for t in indices.chunks_exact(3) {
+            let p = |c: usize| positions[t[c] as usize];
+            if t[0] == t[1] || t[1] == t[2] || t[2] == t[0] {
+                report.degenerate_faces += 1;
+                continue;
+            }
+            if triangle_area(p(0), p(1), p(2)) == 0.0 {
+                report.degenerate_faces += 1;
+            }
+            proper.extend_from_slice(t);
+        }
+
+        // Components as sets of vertices
+        let mut sets = UnionFind::new(positions.len());
+        for t in proper.chunks_exact(3) {
+            sets.union(t[0], t[1]);
+            sets.union(t[0], t[2]);
+        }
+
+        let mut component_of = vec![u32::MAX; positions.len()];
+        for &v in &proper {
+            let root = sets.find(v) as usize;
+            if component_of[root] == u32::MAX {
+                component_of[root] = report.components.len() as u32;
+                report.components.push(ComponentTopology::default());
+            }
+            component_of[v as usize] = component_of[root];
+        }
+        let mut counted = vec![false; positions.len()];
+        for &v in &proper {
+            if !counted[v as usize] {
+                counted[v as usize] = true;
+                report.components[component_of[v as usize] as usize].vertices += 1;
+            }
+        }
+        for t in proper.chunks_exact(3) {
+            report.components[component_of[t[0] as usize] as usize].faces += 1;
+        }
+
+        let edges = edge_faces(&proper);
+        for (&(a, _), faces) in &edges {
+            report.components[component_of[a as usize] as usize].edges += 1;
+            match faces.len() {
+                1 => report.boundary_edges += 1,
+                2 => {}
+                _ => report.non_manifold_edges += 1,
+            }
+        }
+        for boundary_loop in boundary_loops(&edges) {
+            report.components[component_of[boundary_loop[0] as usize] as usize].boundary_loops += 1;
+        }
+
+        // V - E + F = 2 - 2g - b
+        for component in &mut report.components {
+            let euler = component.vertices as i32 - component.edges as i32 + component.faces as i32;
+            component.genus = (2 - component.boundary_loops as i32 - euler) / 2;
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::decl;
+
+    fn analyze(positions: &[[f32; 3]], indices: &[u32]) -> TopologyReport {
+        decl(positions, indices).analyze().unwrap()
+    }
+
+    // `n` x `n` quads, wrapped around into a torus when `closed`
+    fn grid(n: u32, closed: bool) -> (Vec<[f32; 3]>, Vec<u32>) {
+        let (rows, wrap) = if closed { (n, n) } else { (n + 1, n + 1) };
+        let mut positions = vec![];
+        for i in 0..rows {
+            for j in 0..rows {
+                let (a, b) = (i as f32 / n as f32, j as f32 / n as f32);
+                positions.push(if closed {
+                    let (a, b) = (a * std::f32::consts::TAU, b * std::f32::consts::TAU);
+                    let r = 2.0 + b.cos();
+                    [r * a.cos(), r * a.sin(), b.sin()]
+                } else {
+                    [a, b, 0.0]
+                });
+            }
+        }
+        let mut indices = vec![];
+        for i in 0..n {
+            for j in 0..n {
+                let v = |i: u32, j: u32| (i % wrap) * rows + j % wrap;
+                let quad = [v(i, j), v(i + 1, j), v(i + 1, j + 1), v(i, j + 1)];
+                indices.extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+            }
+        }
+        (positions, indices)
+    }
+
+    #[test]
+    fn torus_and_grid() {
+        let (positions, indices) = grid(4, true);
+        let report = analyze(&positions, &indices);
+        assert_eq!(report.components.len(), 1);
+        let torus = &report.components[0];
+        assert_eq!((torus.boundary_loops, torus.genus), (0, 1));
+        assert_eq!(report.boundary_edges, 0);
+
+        let (positions, mut indices) = grid(4, false);
+        // Repeated vertices count as degenerate but aren't part of the surface
+        indices.extend_from_slice(&[0, 0, 1]);
+        let report = analyze(&positions, &indices);
+        assert_eq!(report.degenerate_faces, 1);
+        let grid = &report.components[0];
+        assert_eq!((grid.faces, grid.vertices, grid.edges), (32, 25, 56));
+        assert_eq!((grid.boundary_loops, grid.genus), (1, 0));
+        assert_eq!(report.boundary_edges, 16);
+    }
+
+    #[test]
+    fn loops_touching_at_a_vertex() {
+        // Two triangles that only share vertex 0
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [-1.0, -1.0, 0.0],
+        ];
+        let report = analyze(&positions, &[0, 1, 2, 0, 3, 4]);
+        assert_eq!(report.components.len(), 1);
+        assert_eq!(report.components[0].boundary_loops, 2);
+
+        // A square with a triangular hole whose corner touches the outer boundary
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [2.0, 0.0, 0.0],
+            [2.0, 2.0, 0.0],
+            [0.0, 2.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.5, 1.0, 0.0],
+            [0.5, 1.0, 0.0],
+        ];
+        let indices = [0, 4, 6, 4, 1, 5, 1, 2, 5, 5, 2, 6, 2, 3, 6, 3, 0, 6];
+        let report = analyze(&positions, &indices);
+        let component = &report.components[0];
+        assert_eq!(component.boundary_loops, 2);
+        // V - E + F = 7 - 13 + 6 = 0, an annulus pinched at vertex 4
+        assert_eq!(component.genus, 0);
+    }
+}